@@ -0,0 +1,464 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::http::method::HttpMethod;
+use crate::http::version::HttpVersion;
+use crate::logging::{Logger, civil_time, fields, rfc3339};
+use crate::request::Request;
+
+/// Lines waiting for the writer. Past this, new lines are dropped rather than
+/// letting a slow disk hold on to an unbounded amount of memory.
+const QUEUE_LINES: usize = 8192;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Apache Common Log Format.
+    Common,
+    /// Apache Combined Log Format: Common plus referer and user agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+enum Target {
+    Stdout,
+    File(PathBuf),
+}
+
+/// Configuration for per-request access logs, passed to
+/// [`App::access_log`](crate::App::access_log).
+///
+/// ```no_run
+/// # use server::access_log::{AccessLog, AccessLogFormat};
+/// # use std::time::Duration;
+/// let log = AccessLog::file("logs/access.log", AccessLogFormat::Combined)
+///     .max_size(100 * 1024 * 1024)
+///     .rotate_every(Duration::from_secs(24 * 60 * 60))
+///     .reopen_on_sighup(true);
+/// ```
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    target: Target,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    reopen_on_sighup: bool,
+}
+
+impl AccessLog {
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            target: Target::Stdout,
+            max_size: None,
+            rotate_every: None,
+            reopen_on_sighup: false,
+        }
+    }
+
+    pub fn file(path: impl AsRef<Path>, format: AccessLogFormat) -> Self {
+        Self {
+            target: Target::File(path.as_ref().to_path_buf()),
+            ..Self::stdout(format)
+        }
+    }
+
+    /// Rotates the file once it would grow past `bytes`.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates the file once it has been open for `interval`.
+    pub fn rotate_every(mut self, interval: Duration) -> Self {
+        self.rotate_every = Some(interval);
+        self
+    }
+
+    /// Reopens the file on SIGHUP, for use with an external `logrotate`.
+    pub fn reopen_on_sighup(mut self, reopen: bool) -> Self {
+        self.reopen_on_sighup = reopen;
+        self
+    }
+
+    /// Opens the log and starts the background writer, which reports its
+    /// own failures to `logger`.
+    pub(crate) fn start(&self, logger: Logger) -> io::Result<AccessLogger> {
+        let mut sink = Sink::open(self.target.clone(), self.max_size, self.rotate_every)?;
+        let (tx, mut rx) = mpsc::channel::<Message>(QUEUE_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = Arc::clone(&dropped);
+
+        // Writes are blocking file I/O, so they get their own thread instead of
+        // stalling the request tasks.
+        std::thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                while let Some(message) = rx.blocking_recv() {
+                    let missed = writer_dropped.swap(0, Ordering::Relaxed);
+                    if missed > 0 {
+                        logger.error(
+                            "access log writer fell behind, lines dropped",
+                            fields!["dropped" => missed],
+                        );
+                    }
+                    let result = match message {
+                        Message::Line(line) => sink.write_line(&line),
+                        Message::Reopen => sink.reopen(),
+                    };
+                    if let Err(e) = result {
+                        logger.error("access log write failed", fields!["error" => e]);
+                    }
+                }
+            })?;
+
+        if self.reopen_on_sighup && matches!(self.target, Target::File(_)) {
+            let weak = tx.downgrade();
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    let Some(tx) = weak.upgrade() else { break };
+                    let _ = tx.send(Message::Reopen).await;
+                }
+            });
+        }
+
+        Ok(AccessLogger {
+            format: self.format,
+            tx,
+            dropped,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Message {
+    Line(String),
+    Reopen,
+}
+
+/// Handle used by connections to write access log lines.
+#[derive(Clone, Debug)]
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    tx: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLogger {
+    /// Queues a line for the writer, or drops it if the writer is too far
+    /// behind. Dropped lines are counted and reported once it catches up.
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let line = Message::Line(entry.format(self.format));
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lines dropped because the writer fell behind, not yet reported.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// What gets recorded about one request. Built from the [`Request`] before it
+/// is handed to the router and completed once the response is written.
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry {
+    pub client: Option<IpAddr>,
+    pub method: HttpMethod,
+    /// Request target as sent, including the query string.
+    pub target: String,
+    pub version: HttpVersion,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub time: SystemTime,
+    pub status: usize,
+    /// Body bytes sent.
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+impl AccessLogEntry {
+    pub fn new(request: &Request, client: Option<IpAddr>) -> Self {
+        let target = if request.get_string.is_empty() {
+            request.uri.clone()
+        } else {
+            format!("{}?{}", request.uri, request.get_string)
+        };
+        Self {
+            client,
+            method: request.method.clone(),
+            target,
+            version: request.version,
+            referer: request.header("Referer").cloned(),
+            user_agent: request.header("User-Agent").cloned(),
+            time: SystemTime::now(),
+            status: 0,
+            bytes: 0,
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn finish(&mut self, status: usize, bytes: u64, started: Instant) {
+        self.status = status;
+        self.bytes = bytes;
+        self.latency = started.elapsed();
+    }
+
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+            ),
+            AccessLogFormat::Json => self.json(),
+        }
+    }
+
+    // host ident authuser [date] "request-line" status bytes
+    fn common(&self) -> String {
+        let secs = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (year, month, day, hour, minute, second) = civil_time(secs);
+        format!(
+            "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {}",
+            self.client
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            minute,
+            second,
+            self.method,
+            escape(&self.target),
+            self.version,
+            self.status,
+            if self.bytes == 0 {
+                "-".to_string()
+            } else {
+                self.bytes.to_string()
+            }
+        )
+    }
+
+    fn json(&self) -> String {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            time: String,
+            client: Option<String>,
+            method: String,
+            target: &'a str,
+            version: String,
+            status: usize,
+            bytes: u64,
+            latency_ms: f64,
+            referer: Option<&'a str>,
+            user_agent: Option<&'a str>,
+        }
+
+        serde_json::to_string(&Line {
+            time: rfc3339(self.time),
+            client: self.client.map(|ip| ip.to_string()),
+            method: self.method.to_string(),
+            target: &self.target,
+            version: self.version.to_string(),
+            status: self.status,
+            bytes: self.bytes,
+            latency_ms: (self.latency.as_secs_f64() * 1_000_000.0).round() / 1000.0,
+            referer: self.referer.as_deref(),
+            user_agent: self.user_agent.as_deref(),
+        })
+        .unwrap_or_default()
+    }
+}
+
+/// Escapes quotes, backslashes and control characters so a client can't
+/// forge log lines.
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+struct Sink {
+    target: Target,
+    file: Option<File>,
+    size: u64,
+    opened_at: Instant,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+}
+
+impl Sink {
+    fn open(
+        target: Target,
+        max_size: Option<u64>,
+        rotate_every: Option<Duration>,
+    ) -> io::Result<Self> {
+        let mut sink = Self {
+            target,
+            file: None,
+            size: 0,
+            opened_at: Instant::now(),
+            max_size,
+            rotate_every,
+        };
+        sink.reopen()?;
+        Ok(sink)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        if let Target::File(path) = &self.target {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+            self.opened_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let Target::File(path) = &self.target else {
+            println!("{}", line);
+            return Ok(());
+        };
+
+        let len = line.len() as u64 + 1;
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        let too_old = self
+            .rotate_every
+            .is_some_and(|every| self.opened_at.elapsed() >= every);
+        // A failed rotation still writes the line, to the old file.
+        let rotated = if too_big || too_old {
+            self.rotate(&path.clone())
+        } else {
+            Ok(())
+        };
+
+        if let Some(file) = self.file.as_mut() {
+            writeln!(file, "{}", line)?;
+            self.size += len;
+        }
+        rotated
+    }
+
+    /// Moves the file aside and starts a new one. The old handle is kept
+    /// until the new file is open, so lines keep going somewhere if either
+    /// step fails.
+    fn rotate(&mut self, path: &Path) -> io::Result<()> {
+        fs::rename(path, rotated_path(path))?;
+        self.reopen()
+    }
+}
+
+/// `access.log` becomes `access.log.20250601T120000`, with a counter added
+/// if that name is already taken.
+fn rotated_path(path: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day, hour, minute, second) = civil_time(secs);
+    let stamp = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    );
+
+    let base = format!("{}.{}", path.display(), stamp);
+    let mut candidate = PathBuf::from(&base);
+    let mut n = 1;
+    while candidate.exists() {
+        candidate = PathBuf::from(format!("{}.{}", base, n));
+        n += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        let request: Request = "GET / HTTP/1.1\r\nHost: test\r\n\r\n".parse().unwrap();
+        AccessLogEntry::new(&request, None)
+    }
+
+    #[test]
+    fn counts_lines_dropped_while_the_writer_is_behind() {
+        let (tx, _rx) = mpsc::channel(2);
+        let logger = AccessLogger {
+            format: AccessLogFormat::Common,
+            tx,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        for _ in 0..5 {
+            logger.log(&entry());
+        }
+        assert_eq!(logger.dropped(), 3);
+    }
+
+    #[test]
+    fn rotates_past_max_size() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        let path = dir.join("access.log");
+        let mut sink = Sink::open(Target::File(path.clone()), Some(10), None).unwrap();
+        sink.write_line("first line").unwrap();
+        sink.write_line("second line").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second line\n");
+        let rotated: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| *p != path)
+            .collect();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(fs::read_to_string(&rotated[0]).unwrap(), "first line\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_writing_when_rotation_fails() {
+        let dir = std::env::temp_dir().join(format!("access-log-fail-{}", std::process::id()));
+        let path = dir.join("access.log");
+        let mut sink = Sink::open(Target::File(path.clone()), Some(10), None).unwrap();
+        sink.write_line("first line").unwrap();
+        // The rename target's directory is gone, so rotation fails.
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(sink.write_line("second line").is_err());
+        assert!(sink.file.is_some());
+    }
+}
@@ -0,0 +1,1564 @@
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+use crate::access_log::{AccessLogEntry, AccessLogger};
+use crate::forwarded::TrustedProxies;
+use crate::http::chunked::{ChunkedDecoder, ChunkedError};
+use crate::http::method::HttpMethod;
+use crate::http::status::HttpStatusCode;
+use crate::http::version::HttpVersion;
+use crate::http2::{PREFACE, Rewind, serve_h2, serve_h2c};
+use crate::logging::{Logger, Severity, fields};
+use crate::metrics::Metrics;
+use crate::request::{MAX_URI_LENGTH, ParseError, Request};
+use crate::response::{Body, Response};
+use crate::router::{Dispatch, RouteConfig, Router};
+use crate::tls::TlsInfo;
+
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Accepts an `Upgrade: h2c` request; HTTP/2 frames follow right after it.
+const SWITCHING_TO_H2C: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+/// Settings shared by every connection an [`App`](crate::App) serves.
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    pub version: HttpVersion,
+    pub keep_alive_timeout: Option<Duration>,
+    pub max_requests: Option<usize>,
+    /// HTTP/2 streams a client may have open at once on one connection.
+    pub max_concurrent_streams: u32,
+    pub logger: Logger,
+    pub access_log: Option<AccessLogger>,
+    pub metrics: Option<Arc<Metrics>>,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub trusted_proxies: TrustedProxies,
+    /// [`limits`](Self::limits) raised to the largest per-route override.
+    /// The reader enforces these before it knows which route a request is
+    /// for; the route's own limits are checked once it does.
+    pub read_limits: Limits,
+}
+
+/// Size limits on incoming requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Request line plus header fields; larger heads get 431.
+    pub max_header_bytes: usize,
+    /// Decoded body; larger bodies get 413.
+    pub max_body_bytes: usize,
+    /// Request target; longer targets get 414.
+    pub max_uri_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 64 * 1024,
+            max_body_bytes: 16 * 1024 * 1024,
+            max_uri_length: MAX_URI_LENGTH,
+        }
+    }
+}
+
+impl Limits {
+    /// These limits with the overrides in `config` applied.
+    pub fn with(&self, config: Option<&RouteConfig>) -> Self {
+        let Some(config) = config else {
+            return *self;
+        };
+        Self {
+            max_header_bytes: config.max_header_bytes.unwrap_or(self.max_header_bytes),
+            max_body_bytes: config.max_body_bytes.unwrap_or(self.max_body_bytes),
+            max_uri_length: config.max_uri_length.unwrap_or(self.max_uri_length),
+        }
+    }
+
+    /// Raises each limit to at least the override in `config`.
+    pub fn widen(&mut self, config: &RouteConfig) {
+        let with = self.with(Some(config));
+        self.max_header_bytes = self.max_header_bytes.max(with.max_header_bytes);
+        self.max_body_bytes = self.max_body_bytes.max(with.max_body_bytes);
+        self.max_uri_length = self.max_uri_length.max(with.max_uri_length);
+    }
+}
+
+/// How long each phase of a request may take. `None` waits forever.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// From the first byte of a request to the end of its head; answered
+    /// with 408.
+    pub header_read: Option<Duration>,
+    /// Reading the body; answered with 408.
+    pub body_read: Option<Duration>,
+    /// Running the handler; answered with 503.
+    pub handler: Option<Duration>,
+    /// Writing the response; the connection is closed.
+    pub write: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Some(Duration::from_secs(30)),
+            body_read: Some(Duration::from_secs(60)),
+            handler: None,
+            write: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl Timeouts {
+    /// These timeouts with the overrides in `config` applied.
+    pub fn with(&self, config: Option<&RouteConfig>) -> Self {
+        let Some(config) = config else {
+            return *self;
+        };
+        Self {
+            header_read: self.header_read,
+            body_read: config.body_read_timeout.or(self.body_read),
+            handler: config.handler_timeout.or(self.handler),
+            write: config.write_timeout.or(self.write),
+        }
+    }
+}
+
+/// What to do with new connections once
+/// [`App::max_connections`](crate::App::max_connections) are open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverLimit {
+    /// Stop accepting until a connection closes; new clients wait in the
+    /// listen backlog.
+    #[default]
+    Queue,
+    /// Accept, answer 503 and close. TLS connections are closed without an
+    /// answer.
+    Reject,
+}
+
+/// Addresses of the two ends of an accepted connection, and what TLS
+/// negotiated on it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub tls: Option<TlsInfo>,
+    /// Accepted on a Unix domain socket, so the peer is a local process
+    /// even though it has no address.
+    pub unix_socket: bool,
+}
+
+impl ConnectionInfo {
+    /// Peer address for log fields, `-` when unknown.
+    pub(crate) fn peer(&self) -> String {
+        self.peer_addr
+            .map_or_else(|| "-".to_string(), |addr| addr.to_string())
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            version: HttpVersion::HTTP_1_1,
+            keep_alive_timeout: Some(Duration::from_secs(60)),
+            max_requests: None,
+            max_concurrent_streams: 100,
+            logger: Logger::default(),
+            access_log: None,
+            metrics: None,
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            trusted_proxies: TrustedProxies::default(),
+            read_limits: Limits::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    /// The request could not be parsed or framed; answer with
+    /// [`ParseError::status`] and close.
+    Parse(ParseError),
+}
+
+impl ReadError {
+    /// The status to answer with, if the connection is still writable.
+    pub fn status(&self) -> Option<HttpStatusCode> {
+        match self {
+            Self::Io(e) if e.kind() == io::ErrorKind::TimedOut => {
+                Some(HttpStatusCode::RequestTimeout)
+            }
+            Self::Io(_) => None,
+            Self::Parse(e) => Some(e.status()),
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Parse(e) => write!(f, "bad request: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(value: ParseError) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl From<ChunkedError> for ReadError {
+    fn from(value: ChunkedError) -> Self {
+        Self::Parse(value.into())
+    }
+}
+
+enum BodyFraming {
+    Length(usize),
+    Chunked,
+}
+
+/// A buffered HTTP/1.x connection.
+///
+/// Bytes are read from the underlying stream until a full request (header
+/// block plus body) is available. Anything read past the end of that request
+/// is kept in the buffer and used for the next one, so pipelined requests on
+/// the same connection are not lost.
+pub struct Connection<S> {
+    stream: S,
+    buf: Vec<u8>,
+    idle_timeout: Option<Duration>,
+    shutdown: Option<watch::Receiver<bool>>,
+    limits: Limits,
+    timeouts: Timeouts,
+    head_len: usize,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::with_capacity(READ_CHUNK),
+            idle_timeout: None,
+            shutdown: None,
+            limits: Limits::default(),
+            timeouts: Timeouts::default(),
+            head_len: 0,
+        }
+    }
+
+    /// Timeouts enforced while reading. `body_read` only applies to
+    /// [`read_request`](Self::read_request); callers of
+    /// [`read_body`](Self::read_body) apply their own.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Limits enforced while reading. `max_body_bytes` only applies to
+    /// [`read_request`](Self::read_request); [`read_body`](Self::read_body)
+    /// takes its own.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Size in bytes of the last head returned by [`read_head`](Self::read_head).
+    pub fn head_len(&self) -> usize {
+        self.head_len
+    }
+
+    /// Stops waiting for the next request once `shutdown` turns `true`.
+    pub fn shutdown_signal(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Reads just enough to tell whether the client opened with the HTTP/2
+    /// connection preface. Nothing is consumed.
+    ///
+    /// Like a request head, the preface must arrive within the header
+    /// timeout of its first byte; a client that stalls partway gets
+    /// [`io::ErrorKind::TimedOut`].
+    pub async fn sniff_h2_preface(&mut self) -> io::Result<bool> {
+        if self.buf.is_empty() && !self.wait_for_request().await? {
+            return Ok(false);
+        }
+        within(self.timeouts.header_read, async {
+            while self.buf.len() < PREFACE.len() {
+                if !PREFACE.starts_with(&self.buf) || self.fill().await? == 0 {
+                    return Ok(false);
+                }
+            }
+            Ok(self.buf.starts_with(PREFACE))
+        })
+        .await
+    }
+
+    /// Gives back the stream along with any bytes read but not yet used.
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|rx| *rx.borrow())
+    }
+
+    /// Closes the connection quietly if no new request starts within
+    /// `timeout`. Requests already in progress are not affected.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Reads the next request from the connection.
+    ///
+    /// Returns `Ok(None)` when the peer closed the connection cleanly between
+    /// two requests.
+    pub async fn read_request(&mut self) -> Result<Option<Request>, ReadError> {
+        let Some(mut request) = self.read_head().await? else {
+            return Ok(None);
+        };
+        within(
+            self.timeouts.body_read,
+            self.read_body(&mut request, self.limits.max_body_bytes),
+        )
+        .await?;
+        Ok(Some(request))
+    }
+
+    /// Reads the request line and header fields of the next request, leaving
+    /// the body unread. Follow with [`read_body`](Self::read_body).
+    pub async fn read_head(&mut self) -> Result<Option<Request>, ReadError> {
+        if !self.skip_empty_lines().await? {
+            return Ok(None);
+        }
+
+        // The header timeout starts with the first byte of the request.
+        let header_timeout = self.timeouts.header_read;
+        let head_end = within(header_timeout, async {
+            // Bytes already searched for the end of the head; backed up by
+            // three so a terminator split across reads is still found.
+            let mut scanned: usize = 0;
+            loop {
+                if let Some(pos) = find_head_end(&self.buf, scanned.saturating_sub(3)) {
+                    return Ok::<_, ReadError>(pos);
+                }
+                scanned = self.buf.len();
+                if self.buf.len() > self.limits.max_header_bytes {
+                    return Err(ParseError::HeadersTooLarge.into());
+                }
+                if self.fill().await? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        })
+        .await?;
+
+        if head_end > self.limits.max_header_bytes {
+            return Err(ParseError::HeadersTooLarge.into());
+        }
+        let head =
+            std::str::from_utf8(&self.buf[..head_end]).map_err(|_| ParseError::InvalidUtf8)?;
+        let request = Request::parse(head, self.limits.max_uri_length)?;
+        self.buf.drain(..head_end);
+        self.head_len = head_end;
+
+        // Reject unframeable bodies before anyone answers 100 Continue.
+        body_framing(&request)?;
+        Ok(Some(request))
+    }
+
+    /// Reads the body of `request`, whose head was just returned by
+    /// [`read_head`](Self::read_head). Bodies over `max_bytes` are refused
+    /// with [`ParseError::ContentTooLarge`].
+    pub async fn read_body(
+        &mut self,
+        request: &mut Request,
+        max_bytes: usize,
+    ) -> Result<(), ReadError> {
+        match body_framing(request)? {
+            BodyFraming::Length(len) if len > max_bytes => {
+                return Err(ParseError::ContentTooLarge.into());
+            }
+            BodyFraming::Length(len) => {
+                while self.buf.len() < len {
+                    if self.fill().await? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+                let body = self.buf.drain(..len).collect();
+                request.body = String::from_utf8(body).map_err(|_| ParseError::InvalidBodyUtf8)?;
+            }
+            BodyFraming::Chunked => {
+                let mut decoder = ChunkedDecoder::new()
+                    .max_line_bytes(self.limits.max_header_bytes)
+                    .max_trailer_bytes(self.limits.max_header_bytes);
+                loop {
+                    let used = decoder.decode(&self.buf)?;
+                    self.buf.drain(..used);
+                    if decoder.body().len() > max_bytes {
+                        return Err(ParseError::ContentTooLarge.into());
+                    }
+                    if decoder.is_done() {
+                        break;
+                    }
+                    if self.fill().await? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                }
+                let (body, trailers) = decoder.into_parts();
+                request.set_decoded_body(body, trailers)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the `100 Continue` interim response, unless the client already
+    /// started sending the body without waiting for it.
+    pub async fn send_continue(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Ok(());
+        }
+        self.stream
+            .write_all(
+                format!(
+                    "{} {}\r\n\r\n",
+                    HttpVersion::HTTP_1_1,
+                    HttpStatusCode::Continue
+                )
+                .as_bytes(),
+            )
+            .await?;
+        self.stream.flush().await
+    }
+
+    /// Writes `response` to the connection.
+    ///
+    /// Streamed bodies are sent with chunked encoding on HTTP/1.1; on older
+    /// versions they are written as-is and the caller must close the
+    /// connection afterwards to delimit them.
+    ///
+    /// Returns the number of body bytes sent.
+    pub async fn write_response(
+        &mut self,
+        version: HttpVersion,
+        mut response: Response,
+    ) -> io::Result<u64> {
+        let chunked = response.body().is_streaming() && version == HttpVersion::HTTP_1_1;
+        if chunked {
+            response
+                .headers_mut()
+                .insert("Transfer-Encoding".into(), "chunked".into());
+        } else if response.body().is_streaming() {
+            response
+                .headers_mut()
+                .insert("Connection".into(), "close".into());
+        }
+
+        self.stream
+            .write_all(response.head(version).as_bytes())
+            .await?;
+
+        let mut sent = 0u64;
+        match response.into_body() {
+            Body::Full(body) => {
+                self.stream.write_all(&body).await?;
+                sent = body.len() as u64;
+            }
+            Body::Stream(mut stream) => {
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk?;
+                    self.write_body_chunk(&chunk, chunked).await?;
+                    sent += chunk.len() as u64;
+                }
+                self.finish_body(chunked).await?;
+            }
+            Body::Reader(mut reader) => {
+                let mut buf = vec![0u8; READ_CHUNK];
+                loop {
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    self.write_body_chunk(&buf[..n], chunked).await?;
+                    sent += n as u64;
+                }
+                self.finish_body(chunked).await?;
+            }
+        }
+
+        self.stream.flush().await?;
+        Ok(sent)
+    }
+
+    async fn write_body_chunk(&mut self, chunk: &[u8], chunked: bool) -> io::Result<()> {
+        if chunk.is_empty() {
+            // An empty chunk would terminate a chunked body early.
+            return Ok(());
+        }
+        if chunked {
+            self.stream
+                .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            self.stream.write_all(chunk).await?;
+            self.stream.write_all(b"\r\n").await
+        } else {
+            self.stream.write_all(chunk).await
+        }
+    }
+
+    async fn finish_body(&mut self, chunked: bool) -> io::Result<()> {
+        if chunked {
+            self.stream.write_all(b"0\r\n\r\n").await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the empty lines a client may send before a request, such as a
+    /// stray CRLF after a body (RFC 9112 §2.2), waiting for more while that
+    /// is all there is. Returns `false` like
+    /// [`wait_for_request`](Self::wait_for_request).
+    async fn skip_empty_lines(&mut self) -> io::Result<bool> {
+        loop {
+            let blank = self
+                .buf
+                .chunks_exact(2)
+                .take_while(|pair| *pair == b"\r\n")
+                .count();
+            self.buf.drain(..blank * 2);
+            if !(self.buf.is_empty() || self.buf == b"\r") {
+                return Ok(true);
+            }
+            if !self.wait_for_request().await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Waits for the first bytes of the next request. Returns `false` if the
+    /// connection should be closed instead: the peer hung up, the idle
+    /// timeout expired or the server is shutting down.
+    async fn wait_for_request(&mut self) -> io::Result<bool> {
+        let mut shutdown = self.shutdown.clone();
+        let idle_timeout = self.idle_timeout;
+
+        let read = async {
+            match idle_timeout {
+                Some(idle) => timeout(idle, self.fill()).await.unwrap_or(Ok(0)),
+                None => self.fill().await,
+            }
+        };
+        let shutdown = async {
+            match shutdown.as_mut() {
+                Some(rx) => {
+                    let _ = rx.wait_for(|stop| *stop).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            _ = shutdown => Ok(false),
+            n = read => Ok(n? > 0),
+        }
+    }
+
+    // Cancel-safe: a dropped read leaves the buffer untouched.
+    async fn fill(&mut self) -> io::Result<usize> {
+        self.buf.reserve(READ_CHUNK);
+        self.stream.read_buf(&mut self.buf).await
+    }
+}
+
+/// Serves requests on `stream` until the client or the persistence rules
+/// close the connection.
+///
+/// Once `shutdown` turns `true`, an idle connection is closed right away and
+/// a busy one after the response it is working on.
+pub async fn serve_connection<S>(
+    stream: S,
+    info: ConnectionInfo,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let logger = &options.logger;
+    let peer = info.peer();
+    let mut conn = Connection::new(stream)
+        .limits(options.read_limits)
+        .timeouts(options.timeouts)
+        .idle_timeout(options.keep_alive_timeout)
+        .shutdown_signal(shutdown.clone());
+    let mut served = 0usize;
+
+    if options.version == HttpVersion::HTTP_2_0 {
+        match conn.sniff_h2_preface().await {
+            Ok(true) => {
+                let (stream, buffered) = conn.into_parts();
+                let stream = Rewind::new(buffered, stream);
+                serve_h2(stream, info, router, options, shutdown).await;
+                return;
+            }
+            Ok(false) => {}
+            Err(_) => return,
+        }
+    }
+
+    loop {
+        let mut request = match conn.read_head().await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                fail_read(&mut conn, &options, &peer, e).await;
+                break;
+            }
+        };
+        attach_connection(&mut request, &info, &options.trusted_proxies);
+        let config = router.route_config(&request);
+        let limits = options.limits.with(config);
+        let timeouts = options.timeouts.with(config);
+        if let Err(e) = check_limits(&request, conn.head_len(), &limits) {
+            fail_read(&mut conn, &options, &peer, e.into()).await;
+            break;
+        }
+        // Only on plain connections: over TLS, ALPN picks the protocol.
+        if options.version == HttpVersion::HTTP_2_0 && info.tls.is_none() && wants_h2c(&request) {
+            // The whole request is read before switching; the response goes
+            // out over HTTP/2 on stream 1.
+            let body = conn.read_body(&mut request, limits.max_body_bytes);
+            if let Err(e) = within(timeouts.body_read, body).await {
+                fail_read(&mut conn, &options, &peer, e).await;
+                break;
+            }
+            if let Err(e) = conn.stream_mut().write_all(SWITCHING_TO_H2C).await {
+                logger.error("write failed", fields!["peer" => peer, "error" => e]);
+                break;
+            }
+            let (stream, buffered) = conn.into_parts();
+            serve_h2c(stream, buffered, request, info, router, options, shutdown).await;
+            return;
+        }
+        served += 1;
+        let started = Instant::now();
+        let method = request.method.clone();
+        let path = request.uri.clone();
+
+        // HTTP/1.0 clients get answers in their own version, which also
+        // rules out chunked bodies for them.
+        let version = match request.version {
+            HttpVersion::HTTP_1_0 => HttpVersion::HTTP_1_0,
+            _ => h1_version(options.version),
+        };
+        let client_keep_alive = wants_keep_alive(&request);
+        let mut entry = options
+            .access_log
+            .as_ref()
+            .map(|_| AccessLogEntry::new(&request, request.client_ip));
+
+        let in_flight = options.metrics.as_ref().map(Metrics::request_started);
+        let dispatch = match check_expectation(&router, &request) {
+            // The body was never read, so the connection can't be reused.
+            Some(mut response) => {
+                response
+                    .headers_mut()
+                    .insert("Connection".into(), "close".into());
+                Dispatch {
+                    response,
+                    route: None,
+                    panic: None,
+                }
+            }
+            None => {
+                if expects_continue(&request)
+                    && let Err(e) = conn.send_continue().await
+                {
+                    logger.error("write failed", fields!["peer" => peer, "error" => e]);
+                    break;
+                }
+                let body = conn.read_body(&mut request, limits.max_body_bytes);
+                if let Err(e) = within(timeouts.body_read, body).await {
+                    fail_read(&mut conn, &options, &peer, e).await;
+                    break;
+                }
+                dispatch(&router, &options, request, timeouts.handler, &peer).await
+            }
+        };
+        if let Some(panic) = &dispatch.panic {
+            logger.severe(
+                "handler panicked",
+                fields!["method" => panic.method, "route" => panic.route, "path" => panic.uri, "peer" => peer, "panic" => panic.message],
+            );
+        }
+        let route = dispatch.route;
+        let mut response = dispatch.response;
+
+        let keep_alive = client_keep_alive
+            && !has_token(response.header("Connection"), "close")
+            && !(response.body().is_streaming() && version != HttpVersion::HTTP_1_1)
+            && options.max_requests.is_none_or(|max| served < max)
+            && !conn.is_shutting_down();
+
+        if !keep_alive {
+            response
+                .headers_mut()
+                .insert("Connection".into(), "close".into());
+        } else if version == HttpVersion::HTTP_1_0 {
+            response
+                .headers_mut()
+                .insert("Connection".into(), "keep-alive".into());
+        }
+
+        let status = response.status();
+        let written = within(timeouts.write, conn.write_response(version, response)).await;
+        drop(in_flight);
+        log_request(logger, &method, &path, status, started, &peer);
+        let bytes = written.as_ref().map_or(0, |sent| *sent);
+        if let Some(metrics) = &options.metrics {
+            metrics.observe(&method, route.as_deref(), status, started.elapsed(), bytes);
+        }
+        if let (Some(access_log), Some(entry)) = (&options.access_log, entry.as_mut()) {
+            entry.finish(status.usize(), bytes, started);
+            access_log.log(entry);
+        }
+
+        if let Err(e) = written {
+            logger.error("write failed", fields!["peer" => peer, "error" => e]);
+            break;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+
+    let _ = conn.stream_mut().shutdown().await;
+}
+
+/// Logs a failed read and, if the connection is still writable, answers it
+/// with the matching error status.
+async fn fail_read<S>(
+    conn: &mut Connection<S>,
+    options: &ConnectionOptions,
+    peer: &str,
+    e: ReadError,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    options
+        .logger
+        .error("read failed", fields!["peer" => peer, "error" => e]);
+    if let Some(status) = e.status() {
+        let response = Response::new()
+            .status(status)
+            .header("Connection", "close")
+            .build();
+        let _ = conn
+            .write_response(h1_version(options.version), response)
+            .await;
+    }
+}
+
+/// Records where `request` came from, resolving the client address through
+/// `trusted_proxies`.
+pub(crate) fn attach_connection(
+    request: &mut Request,
+    info: &ConnectionInfo,
+    trusted_proxies: &TrustedProxies,
+) {
+    request.connection = info.clone();
+    request.client_ip = trusted_proxies.client_ip(info, request);
+}
+
+/// Checks a request head against the limits of the route it is for. The
+/// body is checked by its declared length here and again while it is read,
+/// so chunked bodies are covered too.
+pub(crate) fn check_limits(
+    request: &Request,
+    head_len: usize,
+    limits: &Limits,
+) -> Result<(), ParseError> {
+    if head_len > limits.max_header_bytes {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    let target_len = match request.get_string.len() {
+        0 => request.uri.len(),
+        query => request.uri.len() + 1 + query,
+    };
+    if target_len > limits.max_uri_length {
+        return Err(ParseError::UriTooLong);
+    }
+    if let Some(len) = request.header("Content-Length")
+        && parse_content_length(len).is_ok_and(|len| len > limits.max_body_bytes)
+    {
+        return Err(ParseError::ContentTooLarge);
+    }
+    Ok(())
+}
+
+/// Whether the client waits for `100 Continue` before sending the body.
+/// HTTP/1.0 clients can't understand interim responses, so their
+/// expectations are ignored (RFC 9110 §10.1.1).
+pub(crate) fn expects_continue(request: &Request) -> bool {
+    request.version != HttpVersion::HTTP_1_0
+        && request
+            .header("Expect")
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// Decides on an `Expect` header before the body is read. Returns the
+/// response to refuse the request with: 417 for expectations other than
+/// `100-continue`, or whatever [`Router::on_expect`] answered.
+pub(crate) fn check_expectation(router: &Router, request: &Request) -> Option<Response> {
+    if request.version == HttpVersion::HTTP_1_0 {
+        return None;
+    }
+    request.header("Expect")?;
+    if !expects_continue(request) {
+        return Some(
+            Response::new()
+                .status(HttpStatusCode::ExpectationFailed)
+                .build(),
+        );
+    }
+    router.check_expect(request)
+}
+
+/// Dispatches `request` into `router`, except for the metrics path, which is
+/// answered here when metrics are enabled.
+///
+/// A handler still running after `handler_timeout` is dropped and the
+/// request answered with 503.
+pub(crate) async fn dispatch(
+    router: &Router,
+    options: &ConnectionOptions,
+    request: Request,
+    handler_timeout: Option<Duration>,
+    peer: &str,
+) -> Dispatch {
+    if let Some(metrics) = &options.metrics
+        && metrics.matches(&request.method, &request.uri)
+    {
+        let mut response = metrics.response();
+        if request.method == HttpMethod::Head {
+            response.strip_body();
+        }
+        return Dispatch {
+            response,
+            route: Some(metrics.path().to_string()),
+            panic: None,
+        };
+    }
+
+    let Some(limit) = handler_timeout else {
+        return router.dispatch(request).await;
+    };
+    let route = router.matched_route(&request).map(str::to_string);
+    let method = request.method.clone();
+    let path = request.uri.clone();
+    match timeout(limit, router.dispatch(request)).await {
+        Ok(dispatch) => dispatch,
+        Err(_) => {
+            options.logger.error(
+                "handler timed out",
+                fields!["method" => method, "path" => path, "peer" => peer, "timeout_ms" => limit.as_millis()],
+            );
+            Dispatch {
+                response: Response::new()
+                    .status(HttpStatusCode::ServiceUnavailable)
+                    .build(),
+                route,
+                panic: None,
+            }
+        }
+    }
+}
+
+/// Runs `fut`, failing with [`io::ErrorKind::TimedOut`] if it takes longer
+/// than `limit`.
+async fn within<T, E, F>(limit: Option<Duration>, fut: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: From<io::Error>,
+{
+    match limit {
+        Some(limit) => timeout(limit, fut)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+        None => fut.await,
+    }
+}
+
+/// Logs a served request; 5xx answers are logged as errors.
+pub(crate) fn log_request(
+    logger: &Logger,
+    method: &HttpMethod,
+    path: &str,
+    status: HttpStatusCode,
+    started: Instant,
+    peer: &str,
+) {
+    let severity = if status.usize() >= 500 {
+        Severity::Error
+    } else {
+        Severity::Info
+    };
+    if !logger.enabled(severity) {
+        return;
+    }
+    let latency = format!("{:.3}", started.elapsed().as_secs_f64() * 1000.0);
+    logger.log(
+        severity,
+        "request",
+        fields!["method" => method, "path" => path, "status" => status.usize(), "latency_ms" => latency, "peer" => peer],
+    );
+}
+
+/// The version to put in HTTP/1.x status lines when the server is configured
+/// for `version`. HTTP/2 is negotiated separately, so on an HTTP/1 connection
+/// it means HTTP/1.1.
+fn h1_version(version: HttpVersion) -> HttpVersion {
+    match version {
+        HttpVersion::HTTP_0_9 | HttpVersion::HTTP_1_0 => HttpVersion::HTTP_1_0,
+        _ => HttpVersion::HTTP_1_1,
+    }
+}
+
+/// Whether the client asked for (or by default expects) a persistent
+/// connection, per RFC 9112 §9.3.
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.header("Connection");
+    match request.version {
+        HttpVersion::HTTP_1_0 | HttpVersion::HTTP_0_9 => has_token(connection, "keep-alive"),
+        _ => !has_token(connection, "close"),
+    }
+}
+
+/// Whether `request` asks to switch to HTTP/2 with `Upgrade: h2c`
+/// (RFC 7540 §3.2). That takes exactly one valid `HTTP2-Settings` field,
+/// named in `Connection` as well. Requests waiting for `100 Continue` are
+/// served over HTTP/1.1, as are all others.
+fn wants_h2c(request: &Request) -> bool {
+    let connection = request.header("Connection");
+    request.version == HttpVersion::HTTP_1_1
+        && has_token(request.header("Upgrade"), "h2c")
+        && has_token(connection, "upgrade")
+        && has_token(connection, "http2-settings")
+        && request.header("Expect").is_none()
+        && request
+            .header("HTTP2-Settings")
+            .is_some_and(|settings| is_h2_settings(settings))
+}
+
+/// Whether `value` is a base64url-encoded SETTINGS frame payload, without
+/// padding: six bytes per setting. Repeated fields are joined with commas,
+/// which fails the check.
+fn is_h2_settings(value: &str) -> bool {
+    let value = value.trim();
+    value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        && value.len() % 4 != 1
+        && (value.len() * 6 / 8).is_multiple_of(6)
+}
+
+fn has_token(value: Option<&String>, token: &str) -> bool {
+    value.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Position just past the `\r\n\r\n` that terminates the header block,
+/// looking no earlier than `from`.
+fn find_head_end(buf: &[u8], from: usize) -> Option<usize> {
+    buf[from.min(buf.len())..]
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| from + pos + 4)
+}
+
+fn body_framing(request: &Request) -> Result<BodyFraming, ParseError> {
+    if let Some(te) = request.header("Transfer-Encoding") {
+        // A message with both is a request smuggling vector; refuse it.
+        if request.header("Content-Length").is_some() {
+            return Err(ParseError::ConflictingLength);
+        }
+        // Codings such as gzip are never decoded, so only a lone `chunked`
+        // is understood; anything else gets 501 (RFC 9112 §6.1).
+        if !te.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        return Ok(BodyFraming::Chunked);
+    }
+
+    match request.header("Content-Length") {
+        Some(v) => parse_content_length(v).map(BodyFraming::Length),
+        None => Ok(BodyFraming::Length(0)),
+    }
+}
+
+/// `Content-Length = 1*DIGIT`. A list of identical values (from repeated
+/// fields) is accepted; differing values are not.
+fn parse_content_length(value: &str) -> Result<usize, ParseError> {
+    let mut length = None;
+    for v in value.split(',').map(str::trim) {
+        if v.is_empty() || !v.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        let v = v
+            .parse::<usize>()
+            .map_err(|_| ParseError::InvalidContentLength)?;
+        if length.is_some_and(|l| l != v) {
+            return Err(ParseError::InvalidContentLength);
+        }
+        length = Some(v);
+    }
+    length.ok_or(ParseError::InvalidContentLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::{App, LogLevel};
+
+    fn request(head: &str) -> Request {
+        format!("GET / HTTP/1.1\r\nHost: test\r\n{}\r\n", head)
+            .parse()
+            .unwrap()
+    }
+
+    fn framing(head: &str) -> Result<BodyFraming, ParseError> {
+        body_framing(&request(head))
+    }
+
+    #[test]
+    fn refuses_transfer_encoding_with_content_length() {
+        assert!(matches!(
+            framing("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"),
+            Err(ParseError::ConflictingLength)
+        ));
+        assert!(matches!(
+            framing("Content-Length: 5\r\nTransfer-Encoding: gzip, chunked\r\n"),
+            Err(ParseError::ConflictingLength)
+        ));
+    }
+
+    #[test]
+    fn frames_bodies() {
+        assert!(matches!(
+            framing("Transfer-Encoding: Chunked\r\n"),
+            Ok(BodyFraming::Chunked)
+        ));
+        for te in ["gzip, chunked", "chunked, gzip", "chunked, chunked", "gzip"] {
+            let head = format!("Transfer-Encoding: {}\r\n", te);
+            assert!(
+                matches!(framing(&head), Err(ParseError::UnsupportedTransferEncoding)),
+                "{:?}",
+                te
+            );
+        }
+        assert_eq!(
+            ParseError::UnsupportedTransferEncoding.status().usize(),
+            501
+        );
+        assert!(matches!(
+            framing("Content-Length: 5\r\n"),
+            Ok(BodyFraming::Length(5))
+        ));
+        assert!(matches!(framing(""), Ok(BodyFraming::Length(0))));
+        assert!(matches!(
+            framing("Content-Length: 5\r\nContent-Length: 6\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+        assert!(matches!(
+            framing("Content-Length: -1\r\n"),
+            Err(ParseError::InvalidContentLength)
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_request_refuses_conflicting_framing() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: t\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut conn = Connection::new(server);
+        assert!(matches!(
+            conn.read_request().await,
+            Err(ReadError::Parse(ParseError::ConflictingLength))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_bodies_that_are_not_utf8() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: t\r\nContent-Length: 2\r\n\r\n\xff\xfe")
+            .await
+            .unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: t\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n\x80\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut conn = Connection::new(server);
+        for _ in 0..2 {
+            let mut request = conn.read_head().await.unwrap().unwrap();
+            assert!(matches!(
+                conn.read_body(&mut request, 1024).await,
+                Err(ReadError::Parse(ParseError::InvalidBodyUtf8))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_a_stalled_h2_preface() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server).timeouts(Timeouts {
+            header_read: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        });
+        client.write_all(b"PRI * HTTP/2.0\r\n").await.unwrap();
+        let e = conn.sniff_h2_preface().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn sniffs_h2_prefaces() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server);
+        client.write_all(PREFACE).await.unwrap();
+        assert!(conn.sniff_h2_preface().await.unwrap());
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server);
+        client.write_all(b"PUT / HTTP/1.1\r\n").await.unwrap();
+        assert!(!conn.sniff_h2_preface().await.unwrap());
+    }
+
+    #[test]
+    fn recognizes_h2c_upgrades() {
+        let upgrade = "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n";
+        assert!(wants_h2c(&request(upgrade)));
+        // An empty SETTINGS payload is valid too.
+        assert!(wants_h2c(&request(
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: \r\n"
+        )));
+        assert!(!wants_h2c(&request(&format!(
+            "{}Expect: 100-continue\r\n",
+            upgrade
+        ))));
+    }
+
+    #[test]
+    fn rejects_incomplete_h2c_upgrades() {
+        assert!(!wants_h2c(&request(
+            "Connection: Upgrade\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n"
+        )));
+        assert!(!wants_h2c(&request(
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n"
+        )));
+        assert!(!wants_h2c(&request(
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: websocket\r\nHTTP2-Settings: AAMAAABk\r\n"
+        )));
+    }
+
+    #[test]
+    fn checks_h2_settings_payloads() {
+        assert!(is_h2_settings("AAMAAABk"));
+        assert!(is_h2_settings("AAMAAABkAAQAoAAAAAIAAAAA"));
+        assert!(!is_h2_settings("AAMAAAB"));
+        assert!(!is_h2_settings("AAMAAABk, AAMAAABk"));
+        assert!(!is_h2_settings("AAMAAA+k"));
+    }
+
+    #[tokio::test]
+    async fn skips_empty_lines_before_a_request() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server);
+        client
+            .write_all(b"\r\n\r\nGET /a HTTP/1.1\r\nHost: t\r\n\r\n")
+            .await
+            .unwrap();
+        let request = conn.read_head().await.unwrap().unwrap();
+        assert_eq!(request.uri, "/a");
+
+        // A CRLF on its own, then a close, is not a request.
+        client.write_all(b"\r").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.write_all(b"\n").await.unwrap();
+        drop(client);
+        assert!(conn.read_head().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn finds_a_head_split_across_reads() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server);
+        let writer = tokio::spawn(async move {
+            for part in [&b"GET /a HTTP/1.1\r\nHost: t\r\n\r"[..], b"\n"] {
+                client.write_all(part).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            client
+        });
+        let request = conn.read_head().await.unwrap().unwrap();
+        assert_eq!(request.uri, "/a");
+        writer.await.unwrap();
+
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\n", 13), Some(18));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\n", 15), None);
+        assert_eq!(find_head_end(b"\r\n", 8), None);
+    }
+
+    fn app() -> App {
+        App::new(
+            "127.0.0.1",
+            0,
+            Router::new()
+                .get("hello", async || Response::new().body("hello").build())
+                .post("echo", async |r: Request| {
+                    Response::new().body(r.body).build()
+                })
+                .get("slow", async || {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Response::new().body("slow").build()
+                })
+                .get("panic", async || -> Response { panic!("boom") }),
+        )
+    }
+
+    /// Sends `input` to `app` over an in-memory stream and returns all it
+    /// answers until it closes the connection.
+    async fn exchange(app: &App, input: &[u8]) -> String {
+        let streams = app.stream_server(LogLevel::None).unwrap();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let serving =
+            tokio::spawn(async move { streams.serve(server, ConnectionInfo::default()).await });
+        // The server may close before reading everything it was sent.
+        let _ = client.write_all(input).await;
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        serving.await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_413_to_large_bodies() {
+        let mut app = app();
+        app.max_body_bytes(4);
+        let response = exchange(
+            &app,
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 10\r\n\r\n0123456789",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        let response = exchange(
+            &app,
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nTransfer-Encoding: chunked\r\n\r\na\r\n0123456789\r\n0\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+        let response = exchange(
+            &app,
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 4\r\nConnection: close\r\n\r\n0123",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_414_to_long_targets() {
+        let mut app = app();
+        app.max_uri_length(16);
+        let input = format!("GET /{} HTTP/1.1\r\nHost: t\r\n\r\n", "a".repeat(32));
+        let response = exchange(&app, input.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 414 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_431_to_large_heads() {
+        let mut app = app();
+        app.max_header_bytes(128);
+        let input = format!(
+            "GET /hello HTTP/1.1\r\nHost: t\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(256)
+        );
+        let response = exchange(&app, input.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+
+        // A head that never ends is refused once it passes the limit.
+        let input = format!("GET /hello HTTP/1.1\r\nX-Padding: {}", "a".repeat(256));
+        let response = exchange(&app, input.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_alive() {
+        let app = app();
+        let response = exchange(
+            &app,
+            b"GET /hello HTTP/1.1\r\nHost: t\r\n\r\nGET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.matches("HTTP/1.1 200 ").count(), 2, "{}", response);
+    }
+
+    #[tokio::test]
+    async fn closes_after_connection_close() {
+        let app = app();
+        let response = exchange(
+            &app,
+            b"GET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\nGET /hello HTTP/1.1\r\nHost: t\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.matches("HTTP/1.1 200 ").count(), 1, "{}", response);
+        assert!(response.contains("Connection: close"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let mut app = app();
+        app.keep_alive_timeout(Some(Duration::from_millis(50)));
+        let response = timeout(
+            Duration::from_secs(5),
+            exchange(&app, b"GET /hello HTTP/1.1\r\nHost: t\r\n\r\n"),
+        )
+        .await
+        .expect("idle connection left open");
+        assert_eq!(response.matches("HTTP/1.1 200 ").count(), 1, "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_100_continue_before_the_body() {
+        let streams = app().stream_server(LogLevel::None).unwrap();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { streams.serve(server, ConnectionInfo::default()).await });
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: t\r\nExpect: 100-continue\r\nContent-Length: 2\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut interim = [0; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"hi").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhi"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_417_to_unknown_expectations() {
+        let response = exchange(
+            &app(),
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nExpect: 200-ok\r\nContent-Length: 2\r\n\r\nhi",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 417 "), "{}", response);
+        assert!(response.contains("Connection: close"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn lets_the_router_refuse_expected_bodies() {
+        let app = App::new(
+            "127.0.0.1",
+            0,
+            Router::new()
+                .post("echo", async |r: Request| {
+                    Response::new().body(r.body).build()
+                })
+                .on_expect(|_| {
+                    Some(
+                        Response::new()
+                            .status(HttpStatusCode::ExpectationFailed)
+                            .build(),
+                    )
+                }),
+        );
+        let response = exchange(
+            &app,
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 417 "), "{}", response);
+        assert!(!response.contains("100 Continue"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_408_to_slow_requests() {
+        let mut app = app();
+        app.header_read_timeout(Some(Duration::from_millis(50)))
+            .body_read_timeout(Some(Duration::from_millis(50)));
+        let response = exchange(&app, b"GET /hello HTTP/1.1\r\nHost: t\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+
+        let response = exchange(
+            &app,
+            b"POST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 10\r\n\r\nhi",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 408 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_503_to_slow_handlers() {
+        let mut app = app();
+        app.handler_timeout(Some(Duration::from_millis(50)));
+        let response = exchange(
+            &app,
+            b"GET /slow HTTP/1.1\r\nHost: t\r\n\r\nGET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        // The connection is still usable afterwards.
+        assert!(response.contains("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_500_and_closes_after_a_panic() {
+        let response = exchange(
+            &app(),
+            b"GET /panic HTTP/1.1\r\nHost: t\r\n\r\nGET /hello HTTP/1.1\r\nHost: t\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+        assert!(response.contains("Connection: close"), "{}", response);
+        assert!(!response.contains("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn lets_the_router_answer_panics() {
+        let app = App::new(
+            "127.0.0.1",
+            0,
+            Router::new()
+                .get("panic", async || -> Response { panic!("boom") })
+                .on_panic(|panic| {
+                    Response::new()
+                        .status(HttpStatusCode::InternalServerError)
+                        .body(format!("{} failed: {}", panic.route, panic.message))
+                        .build()
+                }),
+        );
+        let response = exchange(&app, b"GET /panic HTTP/1.1\r\nHost: t\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 500 "), "{}", response);
+        assert!(response.ends_with("panic failed: boom"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_head_on_the_metrics_path() {
+        let mut app = app();
+        app.metrics("metrics");
+        let response = exchange(
+            &app,
+            b"HEAD /metrics HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+        assert!(!response.contains("Content-Length: 0\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn ignores_a_stray_crlf_after_a_body() {
+        let response = exchange(
+            &app(),
+            b"\r\nPOST /echo HTTP/1.1\r\nHost: t\r\nContent-Length: 2\r\n\r\nhi\r\nGET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response.matches("HTTP/1.1 200 ").count(), 2, "{}", response);
+    }
+
+    /// Starts `app` on a loopback port; it stops when the sender is dropped.
+    async fn listen(mut app: App) -> (SocketAddr, tokio::sync::oneshot::Sender<()>) {
+        let addr = app.bind().await.unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let _ = app
+                .listen_with_shutdown(LogLevel::None, async {
+                    let _ = stopped.await;
+                })
+                .await;
+        });
+        (addr, stop)
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_over_the_limit() {
+        let mut app = app();
+        app.max_connections(Some(1), OverLimit::Reject);
+        let (addr, _stop) = listen(app).await;
+
+        let mut first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+
+        first
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        first.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn queues_connections_over_the_limit() {
+        let mut app = app();
+        app.max_connections(Some(1), OverLimit::Queue);
+        let (addr, _stop) = listen(app).await;
+
+        let first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        second
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let waiting = timeout(
+            Duration::from_millis(200),
+            second.read_to_string(&mut response),
+        )
+        .await;
+        assert!(waiting.is_err(), "answered while over the limit");
+
+        // Closing the first connection frees its slot.
+        drop(first);
+        second.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::connection::ConnectionInfo;
+use crate::request::Request;
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare
+/// address is a network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidCidr(String);
+
+impl Display for InvalidCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid network {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<Cidr>) -> Self {
+        Self { networks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client that made `request`.
+    ///
+    /// Starting from the peer, each hop the request passed through is
+    /// followed backwards for as long as the address reached so far is a
+    /// trusted proxy. `Forwarded` is used if present, `X-Forwarded-For`
+    /// otherwise. A hop that isn't an IP address (`unknown`, obfuscated
+    /// identifiers) ends the walk at the proxy that reported it.
+    ///
+    /// A Unix socket client has no address but can only be a local process,
+    /// so it is trusted whenever any proxy is. Any other peer without an
+    /// address could be anyone: its headers are ignored and the client is
+    /// unknown.
+    pub fn client_ip(&self, info: &ConnectionInfo, request: &Request) -> Option<IpAddr> {
+        let peer = info.peer_addr.map(|addr| addr.ip());
+        if let Some(peer) = peer
+            && !self.contains(canonical(peer))
+        {
+            return Some(canonical(peer));
+        }
+        if peer.is_none() && (!info.unix_socket || self.is_empty()) {
+            return None;
+        }
+
+        let hops = match request.header("Forwarded") {
+            Some(forwarded) => forwarded_for(forwarded),
+            None => match request.header("X-Forwarded-For") {
+                Some(xff) => xff.split(',').map(|hop| parse_node(hop.trim())).collect(),
+                None => Vec::new(),
+            },
+        };
+        let mut hops = hops.into_iter().rev();
+        let mut client = match peer {
+            Some(peer) => canonical(peer),
+            None => canonical(hops.next().flatten()?),
+        };
+        for hop in hops {
+            match hop {
+                Some(ip) if self.contains(client) => client = canonical(ip),
+                _ => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+/// The `for=` parameter of each element of a `Forwarded` header
+/// (RFC 7239), oldest hop first.
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+/// Parses `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1` or `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+/// IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, compare as
+/// the IPv4 address they carry.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()])
+    }
+
+    fn request(xff: &str) -> Request {
+        format!(
+            "GET / HTTP/1.1\r\nHost: test\r\nX-Forwarded-For: {}\r\n\r\n",
+            xff
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn from(peer: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: Some(peer.parse().unwrap()),
+            ..ConnectionInfo::default()
+        }
+    }
+
+    #[test]
+    fn follows_trusted_proxies() {
+        let ip = proxies().client_ip(&from("10.0.0.1:80"), &request("192.0.2.7, 10.0.0.2"));
+        assert_eq!(ip, Some("192.0.2.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let ip = proxies().client_ip(&from("198.51.100.1:80"), &request("192.0.2.7"));
+        assert_eq!(ip, Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ignores_headers_from_unknown_peers() {
+        let ip = proxies().client_ip(&ConnectionInfo::default(), &request("192.0.2.7"));
+        assert_eq!(ip, None);
+    }
+
+    #[test]
+    fn trusts_unix_socket_peers() {
+        let info = ConnectionInfo {
+            unix_socket: true,
+            ..ConnectionInfo::default()
+        };
+        let ip = proxies().client_ip(&info, &request("192.0.2.7"));
+        assert_eq!(ip, Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(
+            TrustedProxies::default().client_ip(&info, &request("192.0.2.7")),
+            None
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use std::fmt::Display;
+
+use crate::request::{is_tchar, parse_field_line};
+
+/// Incremental decoder for `Transfer-Encoding: chunked` message bodies.
+///
+/// Feed it bytes with [`ChunkedDecoder::decode`] as they arrive; it consumes
+/// what it can and reports how much of the input it used, so the caller can
+/// keep whatever follows the body for the next message.
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: State,
+    body: Vec<u8>,
+    trailers: Vec<(String, String)>,
+    max_line_bytes: usize,
+    max_trailer_bytes: usize,
+    /// Bytes of the current size or trailer line already searched for its
+    /// CRLF, so a line arriving in pieces isn't rescanned from the start.
+    scanned: usize,
+    trailer_bytes: usize,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self {
+            state: State::default(),
+            body: Vec::new(),
+            trailers: Vec::new(),
+            max_line_bytes: DEFAULT_MAX_BYTES,
+            max_trailer_bytes: DEFAULT_MAX_BYTES,
+            scanned: 0,
+            trailer_bytes: 0,
+        }
+    }
+}
+
+/// Default cap on a chunk-size line and on the trailer section.
+const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkedError {
+    InvalidChunkSize,
+    InvalidChunkExtension,
+    MissingChunkTerminator,
+    InvalidTrailer,
+    /// A chunk-size line, with its extensions, is longer than allowed.
+    ChunkLineTooLong,
+    /// The trailer section is larger than allowed.
+    TrailersTooLarge,
+}
+
+impl Display for ChunkedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidChunkSize => write!(f, "invalid chunk size"),
+            Self::InvalidChunkExtension => write!(f, "invalid chunk extension"),
+            Self::MissingChunkTerminator => write!(f, "chunk data not followed by CRLF"),
+            Self::InvalidTrailer => write!(f, "invalid trailer field"),
+            Self::ChunkLineTooLong => write!(f, "chunk size line too long"),
+            Self::TrailersTooLarge => write!(f, "trailer section too large"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkedError {}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Longest chunk-size line accepted, extensions included.
+    pub fn max_line_bytes(mut self, max: usize) -> Self {
+        self.max_line_bytes = max;
+        self
+    }
+
+    /// Largest trailer section accepted, in bytes.
+    pub fn max_trailer_bytes(mut self, max: usize) -> Self {
+        self.max_trailer_bytes = max;
+        self
+    }
+
+    /// Decodes as much of `buf` as possible and returns the number of bytes
+    /// consumed. Call again with the unconsumed bytes followed by more input
+    /// until [`is_done`](Self::is_done).
+    pub fn decode(&mut self, buf: &[u8]) -> Result<usize, ChunkedError> {
+        let mut pos = 0;
+
+        loop {
+            match self.state {
+                State::Size => {
+                    let Some(line) = self.next_line(&buf[pos..]) else {
+                        if buf.len() - pos > self.max_line_bytes {
+                            return Err(ChunkedError::ChunkLineTooLong);
+                        }
+                        return Ok(pos);
+                    };
+                    if line > self.max_line_bytes {
+                        return Err(ChunkedError::ChunkLineTooLong);
+                    }
+                    let size = parse_size_line(&buf[pos..pos + line])?;
+                    pos += line + 2;
+                    self.state = if size == 0 {
+                        State::Trailer
+                    } else {
+                        State::Data(size)
+                    };
+                }
+                State::Data(remaining) => {
+                    let available = buf.len() - pos;
+                    if available == 0 {
+                        return Ok(pos);
+                    }
+                    let n = remaining.min(available);
+                    self.body.extend_from_slice(&buf[pos..pos + n]);
+                    pos += n;
+                    self.state = if n == remaining {
+                        State::DataEnd
+                    } else {
+                        State::Data(remaining - n)
+                    };
+                }
+                State::DataEnd => {
+                    if buf.len() - pos < 2 {
+                        return Ok(pos);
+                    }
+                    if &buf[pos..pos + 2] != b"\r\n" {
+                        return Err(ChunkedError::MissingChunkTerminator);
+                    }
+                    pos += 2;
+                    self.state = State::Size;
+                }
+                State::Trailer => {
+                    let Some(line) = self.next_line(&buf[pos..]) else {
+                        if self.trailer_bytes + (buf.len() - pos) > self.max_trailer_bytes {
+                            return Err(ChunkedError::TrailersTooLarge);
+                        }
+                        return Ok(pos);
+                    };
+                    self.trailer_bytes += line + 2;
+                    if self.trailer_bytes > self.max_trailer_bytes {
+                        return Err(ChunkedError::TrailersTooLarge);
+                    }
+                    if line == 0 {
+                        pos += 2;
+                        self.state = State::Done;
+                    } else {
+                        let field = parse_trailer(&buf[pos..pos + line])?;
+                        self.trailers.push(field);
+                        pos += line + 2;
+                    }
+                }
+                State::Done => return Ok(pos),
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Decoded body so far.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Vec<(String, String)>) {
+        (self.body, self.trailers)
+    }
+
+    /// Length of the line at the start of `buf`, not counting its CRLF.
+    fn next_line(&mut self, buf: &[u8]) -> Option<usize> {
+        // Step back one byte in case the last search ended between CR and LF.
+        let from = self.scanned.saturating_sub(1).min(buf.len());
+        match buf[from..].windows(2).position(|w| w == b"\r\n") {
+            Some(end) => {
+                self.scanned = 0;
+                Some(from + end)
+            }
+            None => {
+                self.scanned = buf.len();
+                None
+            }
+        }
+    }
+}
+
+// chunk-size [ chunk-ext ], where
+// chunk-ext = *( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )
+fn parse_size_line(line: &[u8]) -> Result<usize, ChunkedError> {
+    let digits = line.iter().take_while(|c| c.is_ascii_hexdigit()).count();
+    if digits == 0 {
+        return Err(ChunkedError::InvalidChunkSize);
+    }
+
+    let mut size: usize = 0;
+    for &c in &line[..digits] {
+        let d = (c as char).to_digit(16).unwrap() as usize;
+        size = size
+            .checked_mul(16)
+            .and_then(|s| s.checked_add(d))
+            .ok_or(ChunkedError::InvalidChunkSize)?;
+    }
+
+    validate_extensions(&line[digits..])?;
+    Ok(size)
+}
+
+fn validate_extensions(mut ext: &[u8]) -> Result<(), ChunkedError> {
+    loop {
+        ext = &ext[skip_ws(ext)..];
+        if ext.is_empty() {
+            return Ok(());
+        }
+        if ext[0] != b';' {
+            return Err(ChunkedError::InvalidChunkExtension);
+        }
+        ext = &ext[1..];
+        ext = &ext[skip_ws(ext)..];
+
+        let name = ext.iter().take_while(|c| is_tchar(**c)).count();
+        if name == 0 {
+            return Err(ChunkedError::InvalidChunkExtension);
+        }
+        ext = &ext[name..];
+        ext = &ext[skip_ws(ext)..];
+
+        if ext.first() == Some(&b'=') {
+            ext = &ext[1..];
+            ext = &ext[skip_ws(ext)..];
+            if ext.first() == Some(&b'"') {
+                let mut i = 1;
+                loop {
+                    match ext.get(i) {
+                        Some(b'"') => break,
+                        Some(b'\\') => i += 2,
+                        Some(_) => i += 1,
+                        None => return Err(ChunkedError::InvalidChunkExtension),
+                    }
+                }
+                ext = &ext[i + 1..];
+            } else {
+                let value = ext.iter().take_while(|c| is_tchar(**c)).count();
+                if value == 0 {
+                    return Err(ChunkedError::InvalidChunkExtension);
+                }
+                ext = &ext[value..];
+            }
+        }
+    }
+}
+
+fn skip_ws(s: &[u8]) -> usize {
+    s.iter().take_while(|c| **c == b' ' || **c == b'\t').count()
+}
+
+/// Trailer fields follow the same rules as header fields.
+fn parse_trailer(line: &[u8]) -> Result<(String, String), ChunkedError> {
+    let line = std::str::from_utf8(line).map_err(|_| ChunkedError::InvalidTrailer)?;
+    let (name, value) = parse_field_line(line).map_err(|_| ChunkedError::InvalidTrailer)?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8]) -> Result<ChunkedDecoder, ChunkedError> {
+        let mut decoder = ChunkedDecoder::new();
+        let used = decoder.decode(input)?;
+        assert!(decoder.is_done(), "incomplete after {} bytes", used);
+        Ok(decoder)
+    }
+
+    /// Feeds `input` one byte at a time, the way a slow client would send it.
+    fn decode_bytewise(
+        mut decoder: ChunkedDecoder,
+        input: &[u8],
+    ) -> Result<ChunkedDecoder, ChunkedError> {
+        let mut pending = Vec::new();
+        for &b in input {
+            pending.push(b);
+            let used = decoder.decode(&pending)?;
+            pending.drain(..used);
+        }
+        Ok(decoder)
+    }
+
+    #[test]
+    fn decodes_chunks() {
+        let (body, trailers) = decode_all(b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n")
+            .unwrap()
+            .into_parts();
+        assert_eq!(body, b"hello world");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn decodes_input_split_anywhere() {
+        let input = b"5;name=\"v\"\r\nhello\r\nA\r\n0123456789\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let decoder = decode_bytewise(ChunkedDecoder::new(), input).unwrap();
+        assert!(decoder.is_done());
+        let (body, trailers) = decoder.into_parts();
+        assert_eq!(body, b"hello0123456789");
+        assert_eq!(trailers, vec![("X-Sum".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn reports_bytes_after_the_body_as_unused() {
+        let mut decoder = ChunkedDecoder::new();
+        let input = b"1\r\na\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+        let used = decoder.decode(input).unwrap();
+        assert!(decoder.is_done());
+        assert_eq!(&input[used..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        for input in [
+            &b"\r\n"[..],
+            b"zz\r\n",
+            b"-1\r\n",
+            b" 5\r\nhello\r\n",
+            b"fffffffffffffffffffff\r\n",
+        ] {
+            assert_eq!(
+                ChunkedDecoder::new().decode(input),
+                Err(ChunkedError::InvalidChunkSize),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_chunk_extensions() {
+        for input in [
+            &b"5;\r\n"[..],
+            b"5 x\r\n",
+            b"5;a=\r\n",
+            b"5;a=\"open\r\n",
+            b"5;a b\r\n",
+        ] {
+            assert_eq!(
+                ChunkedDecoder::new().decode(input),
+                Err(ChunkedError::InvalidChunkExtension),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_data_without_terminator() {
+        assert_eq!(
+            ChunkedDecoder::new().decode(b"5\r\nhelloXX0\r\n\r\n"),
+            Err(ChunkedError::MissingChunkTerminator)
+        );
+    }
+
+    #[test]
+    fn caps_chunk_size_lines() {
+        let mut input = b"1;a=".to_vec();
+        input.resize(100, b'b');
+        let mut decoder = ChunkedDecoder::new().max_line_bytes(64);
+        assert_eq!(decoder.decode(&input), Err(ChunkedError::ChunkLineTooLong));
+
+        // Also when the line never ends and arrives in pieces.
+        let decoder = ChunkedDecoder::new().max_line_bytes(64);
+        assert_eq!(
+            decode_bytewise(decoder, &input).map(|_| ()),
+            Err(ChunkedError::ChunkLineTooLong)
+        );
+
+        input.extend_from_slice(b"\r\n");
+        assert_eq!(
+            ChunkedDecoder::new().max_line_bytes(64).decode(&input),
+            Err(ChunkedError::ChunkLineTooLong)
+        );
+    }
+
+    #[test]
+    fn caps_the_trailer_section() {
+        let mut input = b"0\r\n".to_vec();
+        for i in 0..10 {
+            input.extend_from_slice(format!("X-Field-{}: value\r\n", i).as_bytes());
+        }
+        input.extend_from_slice(b"\r\n");
+
+        assert!(decode_all(&input).is_ok());
+        assert_eq!(
+            ChunkedDecoder::new().max_trailer_bytes(64).decode(&input),
+            Err(ChunkedError::TrailersTooLarge)
+        );
+        let decoder = ChunkedDecoder::new().max_trailer_bytes(64);
+        assert_eq!(
+            decode_bytewise(decoder, &input).map(|_| ()),
+            Err(ChunkedError::TrailersTooLarge)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_trailers() {
+        for input in [
+            &b"0\r\nno colon\r\n\r\n"[..],
+            b"0\r\n: empty name\r\n\r\n",
+            b"0\r\nbad name: v\r\n\r\n",
+            b"0\r\nX-Nul: a\0b\r\n\r\n",
+            b"0\r\nX-Ctl: a\x01b\r\n\r\n",
+            b"0\r\nX-Del: \x7f\r\n\r\n",
+            b"0\r\nX-Folded: a\r\n b\r\n\r\n",
+        ] {
+            assert_eq!(
+                ChunkedDecoder::new().decode(input),
+                Err(ChunkedError::InvalidTrailer),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+}
@@ -1,24 +1,66 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+use crate::request::{ParseError, is_token};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum HttpMethod {
     Get,
+    Head,
     Post,
     Patch,
     Put,
     Delete,
     Options,
+    Trace,
+    Connect,
+    /// Any other method token, e.g. `PROPFIND` or `PURGE`.
+    Extension(String),
+}
+
+impl HttpMethod {
+    /// Builds a method from its token. Standard methods map to their own
+    /// variant, so `HttpMethod::extension("GET")` is `HttpMethod::Get`.
+    ///
+    /// Panics if `name` is not a valid method token.
+    pub fn extension(name: &str) -> Self {
+        name.parse().expect("invalid method token")
+    }
 }
 
 impl Display for HttpMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
             Self::Post => write!(f, "POST"),
             Self::Patch => write!(f, "PATCH"),
             Self::Put => write!(f, "PUT"),
             Self::Delete => write!(f, "DELETE"),
             Self::Options => write!(f, "OPTIONS"),
+            Self::Trace => write!(f, "TRACE"),
+            Self::Connect => write!(f, "CONNECT"),
+            Self::Extension(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl FromStr for HttpMethod {
+    type Err = ParseError;
+
+    /// Parses a method token. Methods are case-sensitive.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
+            "POST" => Ok(Self::Post),
+            "PATCH" => Ok(Self::Patch),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "OPTIONS" => Ok(Self::Options),
+            "TRACE" => Ok(Self::Trace),
+            "CONNECT" => Ok(Self::Connect),
+            s if is_token(s) => Ok(Self::Extension(s.to_string())),
+            _ => Err(ParseError::InvalidMethod),
         }
     }
 }
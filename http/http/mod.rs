@@ -1,3 +1,4 @@
+pub mod chunked;
 pub mod method;
 pub mod status;
 pub mod version;
@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::future::poll_fn;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+
+use crate::access_log::AccessLogEntry;
+use crate::connection::{
+    ConnectionInfo, ConnectionOptions, attach_connection, check_expectation, check_limits,
+    dispatch, expects_continue, log_request,
+};
+use crate::http::method::HttpMethod;
+use crate::http::status::HttpStatusCode;
+use crate::http::version::HttpVersion;
+use crate::logging::fields;
+use crate::metrics::Metrics;
+use crate::request::{Request, parse_cookie_header, remove_dot_segments};
+use crate::response::{Body, Response};
+use crate::router::{Dispatch, Router};
+
+/// The client connection preface that starts every HTTP/2 connection.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// ALPN protocol id for HTTP/2 over TLS.
+pub const ALPN_H2: &[u8] = b"h2";
+
+/// Header fields that only make sense on an HTTP/1.x connection and must not
+/// be sent in an HTTP/2 response (RFC 9113 §8.2.2).
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// HEADERS frame that opens stream 1 on a connection upgraded from HTTP/1.1
+/// (RFC 7540 §3.2), flagged END_STREAM | END_HEADERS. `:method GET`,
+/// `:scheme http` and `:path /` are indexes into the HPACK static table, so
+/// the client's compression state is left untouched. The frame only opens
+/// the stream; the request answered on it is the one read over HTTP/1.1.
+const UPGRADE_STREAM: [u8; 12] = [0, 0, 3, 0x1, 0x5, 0, 0, 0, 1, 0x82, 0x86, 0x84];
+
+/// Length of an HTTP/2 frame header.
+const FRAME_HEADER_LEN: usize = 9;
+
+/// Serves HTTP/2 on `io`, dispatching every stream into `router`.
+///
+/// Once `shutdown` turns `true` a GOAWAY is sent and the connection closes
+/// after the streams already open have completed.
+pub async fn serve_h2<S>(
+    io: S,
+    info: ConnectionInfo,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    serve(io, None, info, router, options, shutdown).await
+}
+
+/// Serves HTTP/2 on a connection that asked to switch from HTTP/1.1 with
+/// `Upgrade: h2c` and has been answered with 101 Switching Protocols.
+/// `buffered` holds what was read past the upgrade request, which is
+/// answered on stream 1.
+pub(crate) async fn serve_h2c<S>(
+    mut io: S,
+    mut buffered: Vec<u8>,
+    request: Request,
+    info: ConnectionInfo,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let preface = read_preface(&mut io, &mut buffered);
+    let preface = match options.timeouts.header_read {
+        Some(limit) => timeout(limit, preface)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => preface.await,
+    };
+    let preface_len = match preface {
+        Ok(len) => len,
+        Err(e) => {
+            options.logger.error(
+                "HTTP/2 upgrade failed",
+                fields!["peer" => info.peer(), "error" => e],
+            );
+            return;
+        }
+    };
+    // The client's SETTINGS frame must come first; stream 1 opens right
+    // after it.
+    buffered.splice(preface_len..preface_len, UPGRADE_STREAM);
+    let io = Rewind::new(buffered, io);
+    serve(io, Some(request), info, router, options, shutdown).await
+}
+
+/// Reads until `buf` holds the client connection preface and the SETTINGS
+/// frame that completes it, and returns their combined length.
+async fn read_preface<S>(io: &mut S, buf: &mut Vec<u8>) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    let header_end = PREFACE.len() + FRAME_HEADER_LEN;
+    fill_to(io, buf, header_end).await?;
+    let header = &buf[PREFACE.len()..header_end];
+    // Type 0x4 is SETTINGS.
+    if !buf.starts_with(PREFACE) || header[3] != 0x4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid HTTP/2 connection preface",
+        ));
+    }
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    // No more than the default SETTINGS_MAX_FRAME_SIZE; h2 checks the rest.
+    if len > 16 * 1024 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "HTTP/2 SETTINGS frame too large",
+        ));
+    }
+    fill_to(io, buf, header_end + len).await?;
+    Ok(header_end + len)
+}
+
+/// Reads from `io` until `buf` holds at least `len` bytes.
+async fn fill_to<S>(io: &mut S, buf: &mut Vec<u8>, len: usize) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    while buf.len() < len {
+        buf.reserve(len - buf.len());
+        if io.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(())
+}
+
+/// Serves the HTTP/2 connection on `io`; `upgraded` is the request that
+/// switched it over from HTTP/1.1, if any, and is answered on stream 1.
+async fn serve<S>(
+    io: S,
+    mut upgraded: Option<Request>,
+    info: ConnectionInfo,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let peer = info.peer();
+    let info = Arc::new(info);
+    let handshake = h2::server::Builder::new()
+        .max_header_list_size(options.read_limits.max_header_bytes as u32)
+        .max_concurrent_streams(options.max_concurrent_streams)
+        .handshake(io);
+    let handshake = match options.timeouts.header_read {
+        Some(limit) => timeout(limit, handshake).await,
+        None => Ok(handshake.await),
+    };
+    let mut conn = match handshake {
+        Ok(Ok(conn)) => conn,
+        Ok(Err(e)) => {
+            options.logger.error(
+                "HTTP/2 handshake failed",
+                fields!["peer" => peer, "error" => e],
+            );
+            return;
+        }
+        Err(_) => {
+            options.logger.error(
+                "HTTP/2 handshake failed",
+                fields!["peer" => peer, "error" => io::Error::from(io::ErrorKind::TimedOut)],
+            );
+            return;
+        }
+    };
+
+    let mut streams = JoinSet::new();
+    let mut closing = false;
+    loop {
+        // Counted from when the last stream finished, like the keep-alive
+        // timeout between HTTP/1 requests.
+        let idle = options
+            .keep_alive_timeout
+            .filter(|_| streams.is_empty() && !closing);
+        tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop), if !closing => {
+                conn.graceful_shutdown();
+                closing = true;
+            }
+            // No streams to wait for, so there's nothing to be graceful
+            // about; a client that never answers the shutdown PING would
+            // otherwise keep the connection open.
+            _ = sleep(idle.unwrap_or_default()), if idle.is_some() => {
+                conn.abrupt_shutdown(h2::Reason::NO_ERROR);
+                closing = true;
+            }
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            accepted = conn.accept() => match accepted {
+                Some(Ok((request, respond))) => {
+                    let upgraded = match u32::from(respond.stream_id()) {
+                        1 => upgraded.take(),
+                        _ => None,
+                    };
+                    let router = Arc::clone(&router);
+                    let options = Arc::clone(&options);
+                    let info = Arc::clone(&info);
+                    let peer = peer.clone();
+                    streams.spawn(async move {
+                        if let Err(e) = serve_stream(request, upgraded, respond, router, &options, &info).await {
+                            options
+                                .logger
+                                .error("HTTP/2 stream failed", fields!["peer" => peer, "error" => e]);
+                        }
+                    });
+                }
+                Some(Err(e)) => {
+                    options
+                        .logger
+                        .error("HTTP/2 connection failed", fields!["peer" => peer, "error" => e]);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+    while streams.join_next().await.is_some() {}
+}
+
+async fn serve_stream(
+    request: ::http::Request<RecvStream>,
+    upgraded: Option<Request>,
+    mut respond: SendResponse<Bytes>,
+    router: Arc<Router>,
+    options: &ConnectionOptions,
+    info: &ConnectionInfo,
+) -> Result<(), h2::Error> {
+    let started = Instant::now();
+    let logger = &options.logger;
+    let peer = info.peer();
+    let (parts, recv) = request.into_parts();
+    let head_len = head_len(&parts);
+    // An upgrade request was read in full over HTTP/1.1, body included.
+    let (mut request, recv) = match upgraded {
+        Some(request) => (request, None),
+        None => match into_request(parts) {
+            Ok(request) => (request, Some(recv)),
+            Err(status) => {
+                let head = ::http::Response::builder()
+                    .status(status.usize() as u16)
+                    .body(())
+                    .unwrap();
+                respond.send_response(head, true)?;
+                return Ok(());
+            }
+        },
+    };
+
+    let method = request.method.clone();
+    let path = request.uri.clone();
+    attach_connection(&mut request, info, &options.trusted_proxies);
+    let mut entry = options
+        .access_log
+        .as_ref()
+        .map(|_| AccessLogEntry::new(&request, request.client_ip));
+    let in_flight = options.metrics.as_ref().map(Metrics::request_started);
+    let config = router.route_config(&request);
+    let limits = options.limits.with(config);
+    let timeouts = options.timeouts.with(config);
+    let refused = match check_limits(&request, head_len, &limits) {
+        Err(e) => Some(Response::new().status(e.status()).build()),
+        Ok(()) => check_expectation(&router, &request),
+    };
+    let dispatch = match refused {
+        // Dropping the receive stream tells the client to stop sending.
+        Some(response) => Dispatch {
+            response,
+            route: None,
+            panic: None,
+        },
+        None => {
+            let body = match recv {
+                Some(recv) => {
+                    if expects_continue(&request) {
+                        let interim = ::http::Response::builder()
+                            .status(HttpStatusCode::Continue.usize() as u16)
+                            .body(())
+                            .unwrap();
+                        respond.send_informational(interim)?;
+                    }
+                    let body = read_body(recv, limits.max_body_bytes);
+                    match timeouts.body_read {
+                        Some(limit) => timeout(limit, body)
+                            .await
+                            .unwrap_or(Ok(Err(HttpStatusCode::RequestTimeout)))?,
+                        None => body.await?,
+                    }
+                }
+                None => Ok(std::mem::take(&mut request.body)),
+            };
+            match body {
+                Ok(body) => {
+                    request.body = body;
+                    dispatch(&router, options, request, timeouts.handler, &peer).await
+                }
+                Err(status) => Dispatch {
+                    response: Response::new().status(status).build(),
+                    route: None,
+                    panic: None,
+                },
+            }
+        }
+    };
+    if let Some(panic) = &dispatch.panic {
+        logger.severe(
+            "handler panicked",
+            fields!["method" => panic.method, "route" => panic.route, "path" => panic.uri, "peer" => peer, "panic" => panic.message],
+        );
+    }
+    let route = dispatch.route;
+    let response = dispatch.response;
+    let status = response.status();
+    log_request(logger, &method, &path, status, started, &peer);
+
+    let mut head = ::http::Response::builder().status(status.usize() as u16);
+    for (k, v) in response.headers() {
+        if CONNECTION_SPECIFIC.contains(&k.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+        head = head.header(k.to_ascii_lowercase(), v);
+    }
+    for (k, v) in response.cookies() {
+        head = head.header("set-cookie", format!("{}={}", k, v));
+    }
+    let head = match head.body(()) {
+        Ok(head) => head,
+        Err(_) => {
+            respond.send_reset(h2::Reason::INTERNAL_ERROR);
+            return Ok(());
+        }
+    };
+
+    let send = send_body(respond, head, response.into_body());
+    let sent = match timeouts.write {
+        // Dropping the stream midway resets it.
+        Some(limit) => timeout(limit, send)
+            .await
+            .unwrap_or_else(|_| Err(h2::Reason::CANCEL.into())),
+        None => send.await,
+    };
+    drop(in_flight);
+    let bytes = sent.as_ref().map_or(0, |sent| *sent);
+    if let Some(metrics) = &options.metrics {
+        metrics.observe(&method, route.as_deref(), status, started.elapsed(), bytes);
+    }
+    if let (Some(access_log), Some(entry)) = (&options.access_log, entry.as_mut()) {
+        entry.finish(status.usize(), bytes, started);
+        access_log.log(entry);
+    }
+    sent.map(|_| ())
+}
+
+/// Sends the response head and `body`, returning the number of body bytes
+/// sent. A body that fails midway resets the stream.
+async fn send_body(
+    mut respond: SendResponse<Bytes>,
+    head: ::http::Response<()>,
+    body: Body,
+) -> Result<u64, h2::Error> {
+    let mut sent = 0u64;
+    match body {
+        Body::Full(body) if body.is_empty() => {
+            respond.send_response(head, true)?;
+        }
+        Body::Full(body) => {
+            let mut send = respond.send_response(head, false)?;
+            sent = body.len() as u64;
+            send_data(&mut send, Bytes::from(body)).await?;
+            send.send_data(Bytes::new(), true)?;
+        }
+        Body::Stream(mut stream) => {
+            let mut send = respond.send_response(head, false)?;
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        sent += chunk.len() as u64;
+                        send_data(&mut send, Bytes::from(chunk)).await?
+                    }
+                    Err(_) => {
+                        send.send_reset(h2::Reason::INTERNAL_ERROR);
+                        return Ok(sent);
+                    }
+                }
+            }
+            send.send_data(Bytes::new(), true)?;
+        }
+        Body::Reader(mut reader) => {
+            let mut send = respond.send_response(head, false)?;
+            let mut buf = vec![0u8; 16 * 1024];
+            loop {
+                match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        sent += n as u64;
+                        send_data(&mut send, Bytes::copy_from_slice(&buf[..n])).await?
+                    }
+                    Err(_) => {
+                        send.send_reset(h2::Reason::INTERNAL_ERROR);
+                        return Ok(sent);
+                    }
+                }
+            }
+            send.send_data(Bytes::new(), true)?;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Sends `data`, waiting for flow-control window instead of buffering it all
+/// inside the connection.
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), h2::Error> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Err(h2::Error::from(h2::Reason::STREAM_CLOSED)),
+        };
+        if capacity == 0 {
+            continue;
+        }
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+/// Converts the head of an HTTP/2 request into the [`Request`] routes expect,
+/// with an empty body. Methods the router has no tree for get 501, paths
+/// climbing above the root 400.
+fn into_request(parts: ::http::request::Parts) -> Result<Request, HttpStatusCode> {
+    let method = parts
+        .method
+        .as_str()
+        .parse::<HttpMethod>()
+        .map_err(|_| HttpStatusCode::NotImplemented)?;
+
+    let mut headers = HashMap::new();
+    let mut cookies = HashMap::new();
+    if let Some(authority) = parts.uri.authority() {
+        headers.insert("host".to_string(), authority.to_string());
+    }
+    for (name, value) in &parts.headers {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        if name == ::http::header::COOKIE {
+            parse_cookie_header(&value, &mut cookies);
+            continue;
+        }
+        headers
+            .entry(name.as_str().to_string())
+            .and_modify(|v: &mut String| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    // CONNECT carries only an authority, no path (RFC 9113 §8.5).
+    let uri = match (&method, parts.uri.authority()) {
+        (HttpMethod::Connect, Some(authority)) => authority.to_string(),
+        // `OPTIONS *` has no segments to resolve.
+        _ if parts.uri.path() == "*" => "*".to_string(),
+        _ => remove_dot_segments(parts.uri.path()).ok_or(HttpStatusCode::BadRequest)?,
+    };
+
+    Ok(Request {
+        method,
+        version: HttpVersion::HTTP_2_0,
+        uri,
+        get_string: parts.uri.query().unwrap_or_default().to_string(),
+        headers,
+        cookies,
+        body: String::new(),
+        connection: ConnectionInfo::default(),
+        client_ip: None,
+    })
+}
+
+/// Reads a request body, or gives the status to refuse it with once it grows
+/// past `max_bytes` or turns out not to be UTF-8.
+async fn read_body(
+    mut recv: RecvStream,
+    max_bytes: usize,
+) -> Result<Result<String, HttpStatusCode>, h2::Error> {
+    let mut body = Vec::new();
+    while let Some(chunk) = recv.data().await {
+        let chunk = chunk?;
+        let _ = recv.flow_control().release_capacity(chunk.len());
+        if body.len() + chunk.len() > max_bytes {
+            return Ok(Err(HttpStatusCode::ContentTooLarge));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8(body).map_err(|_| HttpStatusCode::BadRequest))
+}
+
+/// Size of a request head as HTTP/2 counts it for
+/// `SETTINGS_MAX_HEADER_LIST_SIZE`: each field plus 32 bytes of overhead.
+fn head_len(parts: &::http::request::Parts) -> usize {
+    let fields: usize = parts
+        .headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 32)
+        .sum();
+    fields + parts.uri.to_string().len()
+}
+
+/// A stream that replays bytes already read from `inner` before reading
+/// from it again, so a protocol sniffed by the HTTP/1 reader can be handed
+/// over untouched.
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = (self.prefix.len() - self.pos).min(buf.remaining());
+            let start = self.pos;
+            buf.put_slice(&self.prefix[start..start + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
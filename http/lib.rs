@@ -1,25 +1,181 @@
 #![allow(dead_code)]
 
+pub mod access_log;
+pub mod connection;
 pub mod database;
+pub mod forwarded;
 pub mod http;
+pub mod http2;
+mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod proxy_protocol;
 pub mod request;
 pub mod response;
 pub mod router;
+mod systemd;
+pub mod tls;
+mod upgrade;
 
-use std::str::FromStr;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
+use access_log::AccessLog;
+use connection::{ConnectionInfo, ConnectionOptions, OverLimit, serve_connection};
+use forwarded::{Cidr, InvalidCidr, TrustedProxies};
 use http::version::HttpVersion;
-use request::Request;
+use http2::{Rewind, serve_h2};
+use listener::Listener;
+use logging::{Logger, fields};
+use metrics::Metrics;
 use router::Router;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tls::{TlsConfig, TlsInfo};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::sync::{Semaphore, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use upgrade::Upgrade;
+
+/// How long to stop accepting after `accept` fails.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Sent to clients turned away by [`OverLimit::Reject`].
+const REJECTED: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
 pub struct App {
     address: &'static str,
     port: u32,
     router: Arc<Router>,
-    version: HttpVersion,
+    options: ConnectionOptions,
+    shutdown_timeout: Duration,
+    tls: Option<TlsConfig>,
+    access_log: Option<AccessLog>,
+    metrics: Option<Arc<Metrics>>,
+    max_connections: Option<(usize, OverLimit)>,
+    proxy_protocol: bool,
+    unix_socket: Option<(PathBuf, u32)>,
+    addresses: Vec<SocketAddr>,
+    listeners: Vec<Listener>,
+    /// `Some` when enabled, with the socket name to pick, if any.
+    socket_activation: Option<Option<String>>,
+    upgrade_on_sigusr2: bool,
+    bound: bool,
+}
+
+/// What the connections of a running [`App`] share.
+#[derive(Clone)]
+struct Server {
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    acceptor: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+}
+
+impl Server {
+    /// Serves one accepted connection: reads the PROXY protocol header if
+    /// one is expected, performs the TLS handshake if configured, then
+    /// speaks HTTP/1 or HTTP/2 until the connection closes.
+    async fn serve<S>(self, socket: S, mut info: ConnectionInfo, shutdown: watch::Receiver<bool>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Self {
+            router,
+            options,
+            acceptor,
+            proxy_protocol,
+        } = self;
+        let _open = options.metrics.as_ref().map(Metrics::connection_opened);
+
+        let socket = if proxy_protocol {
+            let header = match options.timeouts.header_read {
+                Some(limit) => tokio::time::timeout(limit, proxy_protocol::accept(socket))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                None => proxy_protocol::accept(socket).await,
+            };
+            match header {
+                Ok((header, socket)) => {
+                    if let Some(source) = header.source {
+                        info.peer_addr = Some(source);
+                    }
+                    if let Some(destination) = header.destination {
+                        info.local_addr = Some(destination);
+                    }
+                    socket
+                }
+                Err(e) => {
+                    options.logger.error(
+                        "invalid PROXY protocol header",
+                        fields!["peer" => info.peer(), "error" => e],
+                    );
+                    return;
+                }
+            }
+        } else {
+            Rewind::new(Vec::new(), socket)
+        };
+
+        let Some(acceptor) = acceptor else {
+            serve_connection(socket, info, router, options, shutdown).await;
+            return;
+        };
+        let handshake = match options.timeouts.header_read {
+            Some(limit) => tokio::time::timeout(limit, acceptor.accept(socket))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => acceptor.accept(socket).await,
+        };
+        match handshake {
+            Ok(stream) => {
+                let tls = TlsInfo::from_connection(stream.get_ref().1);
+                let h2 = tls.alpn.as_deref() == Some("h2");
+                info.tls = Some(tls);
+                if h2 {
+                    serve_h2(stream, info, router, options, shutdown).await
+                } else {
+                    serve_connection(stream, info, router, options, shutdown).await
+                }
+            }
+            Err(e) => options.logger.error(
+                "TLS handshake failed",
+                fields!["peer" => info.peer(), "error" => e],
+            ),
+        }
+    }
+}
+
+/// Serves single connections for an [`App`]; see
+/// [`App::stream_server`].
+#[derive(Clone)]
+pub struct StreamServer {
+    server: Server,
+    _shutdown_tx: Arc<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl StreamServer {
+    /// Serves `stream` and returns once the connection closes.
+    ///
+    /// `info` says who is on the other end. Forwarding headers are only
+    /// believed when it names a trusted proxy as the peer, or marks the
+    /// stream as a Unix socket; with [`ConnectionInfo::default()`] the client
+    /// is unknown.
+    pub async fn serve<S>(&self, stream: S, info: ConnectionInfo)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.server
+            .clone()
+            .serve(stream, info, self.shutdown_rx.clone())
+            .await
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -36,65 +192,565 @@ impl App {
             address,
             port,
             router: Arc::new(router),
-            version: HttpVersion::HTTP_1_1,
+            options: ConnectionOptions::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+            access_log: None,
+            metrics: None,
+            max_connections: None,
+            proxy_protocol: false,
+            unix_socket: None,
+            addresses: Vec::new(),
+            listeners: Vec::new(),
+            socket_activation: None,
+            upgrade_on_sigusr2: false,
+            bound: false,
         }
     }
 
-    pub async fn listen(&mut self, log_level: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let listener = TcpListener::bind(format!("{}:{}", self.address, self.port)).await?;
-
-        if log_level {
-            println!("Listening on {}:{}", self.address, self.port);
+    /// Binds every configured listener and returns the local address of
+    /// each TCP one, with the real port when port 0 was asked for. Called by
+    /// [`listen`](Self::listen) if it hasn't been already, so tests can bind
+    /// to port 0 first and learn where to connect:
+    ///
+    /// ```no_run
+    /// # async fn run(router: server::router::Router) -> std::io::Result<()> {
+    /// let mut app = server::App::new("127.0.0.1", 0, router);
+    /// let addr = app.bind().await?[0];
+    /// tokio::spawn(async move {
+    ///     let _ = app.listen(server::LogLevel::None).await;
+    /// });
+    /// // connect to `addr`...
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn bind(&mut self) -> io::Result<Vec<SocketAddr>> {
+        if !self.bound {
+            let inherited = match &self.socket_activation {
+                Some(name) => systemd::listen_fds(name.as_deref())?,
+                None if self.upgrade_on_sigusr2 => systemd::listen_fds(None)?,
+                None => Vec::new(),
+            };
+            if !inherited.is_empty() {
+                self.listeners.extend(inherited);
+                self.bound = true;
+            }
         }
-
-        let router = &self.router;
-        loop {
-            let (mut socket, addr) = listener.accept().await?;
-            if log_level {
-                println!("From: {}", addr);
+        if !self.bound {
+            let defaults = self.addresses.is_empty()
+                && self.unix_socket.is_none()
+                && self.listeners.is_empty();
+            if let Some((path, mode)) = &self.unix_socket {
+                self.listeners.push(Listener::bind_unix(path, *mode).await?);
+            }
+            let addresses = if defaults {
+                self.default_addresses()?
+            } else {
+                self.addresses.clone()
+            };
+            for &addr in &addresses {
+                // `[::]` is dual-stack unless IPv4 is bound separately on the
+                // same port, which would otherwise conflict with it.
+                let v6_only = addr.ip().is_unspecified()
+                    && addresses.contains(&SocketAddr::new(
+                        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                        addr.port(),
+                    ));
+                self.listeners.push(Listener::bind_tcp(addr, v6_only)?);
             }
-            let router_clone = Arc::clone(router);
-            let version = self.version;
+            self.bound = true;
+        }
+        Ok(self
+            .listeners
+            .iter()
+            .filter_map(Listener::local_addr)
+            .collect())
+    }
 
-            tokio::spawn(async move {
-                let mut buf = [0u8; 1024];
+    /// The address and port given to [`new`](Self::new).
+    fn default_addresses(&self) -> io::Result<Vec<SocketAddr>> {
+        let port = u16::try_from(self.port)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+        // Accept bracketed IPv6 literals as written in URLs: `[::1]`.
+        let host = self
+            .address
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(self.address);
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
 
-                loop {
-                    let n = match socket.read(&mut buf).await {
-                        Ok(0) => break,
-                        Ok(n) => n,
-                        Err(e) => {
-                            if log_level {
-                                eprintln!("Read error: {}", e);
-                            }
-                            break;
-                        }
-                    };
+    pub async fn listen(&mut self, log_level: LogLevel) -> Result<(), Box<dyn std::error::Error>> {
+        self.listen_with_shutdown(log_level, std::future::pending())
+            .await
+    }
+
+    /// Like [`listen`](Self::listen), but stops once `signal` completes.
+    ///
+    /// The listener is closed first, then open connections get up to
+    /// [`shutdown_timeout`](Self::shutdown_timeout) to finish the request they
+    /// are serving before they are dropped.
+    ///
+    /// ```no_run
+    /// # async fn run(mut app: server::App) {
+    /// app.listen_with_shutdown(server::LogLevel::All, async {
+    ///     let _ = tokio::signal::ctrl_c().await;
+    /// })
+    /// .await
+    /// .unwrap();
+    /// # }
+    /// ```
+    pub async fn listen_with_shutdown<F>(
+        &mut self,
+        log_level: LogLevel,
+        signal: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Future<Output = ()>,
+    {
+        self.bind().await?;
+        let mut listeners = std::mem::take(&mut self.listeners);
+        self.bound = false;
 
-                    let request =
-                        Request::from_str(std::str::from_utf8(&buf[0..n]).unwrap()).unwrap();
-                    let response = router_clone.handle(request).await;
+        let server = self.start(log_level)?;
+        let logger = server.options.logger.clone();
+        let scheme = if server.acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        for listener in &listeners {
+            logger.info("listening", fields!["address" => listener.url(scheme)]);
+        }
+        // If an old process handed us its sockets, it can stop accepting now.
+        upgrade::notify_parent().await;
+
+        let limiter = self
+            .max_connections
+            .map(|(max, over_limit)| (Arc::new(Semaphore::new(max)), over_limit));
 
-                    println!("{}", response);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        let mut next_listener = 0;
+        let mut upgrade_signal = if self.upgrade_on_sigusr2 {
+            Some(tokio::signal::unix::signal(
+                tokio::signal::unix::SignalKind::user_defined2(),
+            )?)
+        } else {
+            None
+        };
+        let mut upgrade: Option<Upgrade> = None;
+        tokio::pin!(signal);
 
-                    match socket
-                        .write_all(format!("{} {}", version, response).as_bytes())
-                        .await
-                    {
-                        Ok(_) => {}
+        loop {
+            tokio::select! {
+                _ = &mut signal => break,
+                Some(()) = async {
+                    match upgrade_signal.as_mut() {
+                        Some(upgrade_signal) => upgrade_signal.recv().await,
+                        None => std::future::pending().await,
+                    }
+                }, if upgrade.is_none() => {
+                    match upgrade::spawn(&listeners) {
+                        Ok(started) => {
+                            logger.info(
+                                "upgrade started",
+                                fields!["pid" => started.pid().unwrap_or_default()],
+                            );
+                            upgrade = Some(started);
+                        }
+                        Err(e) => logger.severe("upgrade failed", fields!["error" => e]),
+                    }
+                }
+                ready = async {
+                    match upgrade.as_mut() {
+                        Some(upgrade) => upgrade.ready().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if ready {
+                        // The new process owns the sockets now; leave them be.
+                        listeners.iter_mut().for_each(Listener::disown);
+                        logger.info("upgrade complete, draining", fields![]);
+                        break;
+                    }
+                    logger.severe("upgrade failed", fields!["error" => "new process exited"]);
+                    upgrade = None;
+                }
+                (accepted, queued) = async {
+                    // In queue mode, wait for a free slot before accepting.
+                    let permit = match &limiter {
+                        Some((slots, OverLimit::Queue)) => {
+                            Arc::clone(slots).acquire_owned().await.ok()
+                        }
+                        _ => None,
+                    };
+                    (listener::accept(&listeners, &mut next_listener).await, permit)
+                } => {
+                    let (mut socket, info) = match accepted {
+                        Ok(accepted) => accepted,
+                        // Usually out of file descriptors or a connection
+                        // aborted before it was accepted. Neither is fatal:
+                        // pause so a full descriptor table can drain instead
+                        // of spinning on it, then keep accepting.
                         Err(e) => {
-                            if log_level {
-                                println!("{}", e);
+                            logger.severe("accept failed", fields!["error" => e]);
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let permit = match &limiter {
+                        Some((slots, OverLimit::Reject)) => {
+                            match Arc::clone(slots).try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    logger.error(
+                                        "connection limit reached",
+                                        fields!["peer" => info.peer()],
+                                    );
+                                    if server.acceptor.is_none() {
+                                        connections.spawn(async move {
+                                            let _ = socket.write_all(REJECTED).await;
+                                            let _ = socket.shutdown().await;
+                                        });
+                                    }
+                                    continue;
+                                }
                             }
                         }
+                        _ => queued,
                     };
+                    logger.info("connection opened", fields!["peer" => info.peer()]);
+
+                    let server = server.clone();
+                    let shutdown = shutdown_rx.clone();
+                    connections.spawn(async move {
+                        let _permit = permit;
+                        server.serve(socket, info, shutdown).await
+                    });
                 }
-            });
+                // Reap finished connections so the set doesn't grow forever.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+
+        drop(listeners);
+        logger.info("shutting down", fields!["connections" => connections.len()]);
+        let _ = shutdown_tx.send(true);
+
+        let drain = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.shutdown_timeout, drain)
+            .await
+            .is_err()
+        {
+            logger.error(
+                "shutdown timeout reached, dropping connections",
+                fields!["connections" => connections.len()],
+            );
+            connections.shutdown().await;
         }
+
+        Ok(())
+    }
+
+    /// Sets up serving connections that were accepted elsewhere, such as one
+    /// half of a [`tokio::io::duplex`] pair or a socket from another
+    /// listener. The same routes, limits, TLS and logging apply as for
+    /// [`listen`](Self::listen).
+    ///
+    /// Certificates are loaded and the access log opened once, here; the
+    /// returned [`StreamServer`] is cheap to clone and serves any number of
+    /// streams.
+    ///
+    /// ```no_run
+    /// # async fn run(app: server::App) -> std::io::Result<()> {
+    /// use server::connection::ConnectionInfo;
+    /// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    ///
+    /// let streams = app.stream_server(server::LogLevel::None)?;
+    /// let (mut client, server) = tokio::io::duplex(64 * 1024);
+    /// tokio::spawn(async move { streams.serve(server, ConnectionInfo::default()).await });
+    /// client
+    ///     .write_all(b"GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+    ///     .await?;
+    /// let mut response = String::new();
+    /// client.read_to_string(&mut response).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_server(&self, log_level: LogLevel) -> io::Result<StreamServer> {
+        // Never signalled, but it must stay alive: a closed channel reads as
+        // a shutdown.
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        Ok(StreamServer {
+            server: self.start(log_level)?,
+            _shutdown_tx: Arc::new(shutdown_tx),
+            shutdown_rx,
+        })
     }
 
+    /// Builds what the connections of one run of the server share.
+    fn start(&self, log_level: LogLevel) -> io::Result<Server> {
+        let alpn = if self.options.version == HttpVersion::HTTP_2_0 {
+            vec![http2::ALPN_H2.to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        let acceptor = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.acceptor(alpn))
+            .transpose()?;
+
+        let mut options = self.options.clone();
+        options.logger = Logger::new(log_level);
+        options.access_log = self
+            .access_log
+            .as_ref()
+            .map(|log| log.start(options.logger.clone()))
+            .transpose()?;
+        options.metrics = self.metrics.clone();
+        options.read_limits = options.limits;
+        for config in self.router.route_configs() {
+            options.read_limits.widen(config);
+        }
+
+        Ok(Server {
+            router: Arc::clone(&self.router),
+            options: Arc::new(options),
+            acceptor,
+            proxy_protocol: self.proxy_protocol,
+        })
+    }
+
+    /// Highest protocol version to serve. With [`HttpVersion::HTTP_2_0`],
+    /// clients can use HTTP/2 through ALPN on TLS or by sending the HTTP/2
+    /// preface directly on a plain connection (prior knowledge) or by asking
+    /// to switch with `Upgrade: h2c`; others keep using HTTP/1.1.
     pub fn version(&mut self, version: HttpVersion) -> &mut Self {
-        self.version = version;
+        self.options.version = version;
+        self
+    }
+
+    /// How long an idle persistent connection is kept open while waiting for
+    /// the next request. `None` keeps it open until the client closes it.
+    pub fn keep_alive_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// How long a client may take to send a request head once it has started
+    /// it. Slower clients get 408 and the connection is closed. The PROXY
+    /// protocol header, the TLS handshake and the HTTP/2 connection preface
+    /// must each arrive within the same time.
+    pub fn header_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.timeouts.header_read = timeout;
+        self
+    }
+
+    /// How long a client may take to send a request body. Slower clients get
+    /// 408 and the connection is closed.
+    pub fn body_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.timeouts.body_read = timeout;
+        self
+    }
+
+    /// How long a handler may run. Handlers still running are dropped and the
+    /// request is answered with 503.
+    pub fn handler_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.timeouts.handler = timeout;
+        self
+    }
+
+    /// How long writing a response may take before the connection is closed.
+    ///
+    /// Body and handler timeouts, like the write timeout, can be overridden
+    /// per route with [`Router::configure`](router::Router::configure).
+    pub fn write_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.options.timeouts.write = timeout;
+        self
+    }
+
+    /// Proxies allowed to report the client address. Requests from these
+    /// networks get [`Request::client_ip`](request::Request::client_ip) from
+    /// their `Forwarded` or `X-Forwarded-For` header instead of the peer
+    /// address.
+    ///
+    /// ```no_run
+    /// # fn configure(app: &mut server::App) -> Result<(), server::forwarded::InvalidCidr> {
+    /// app.trusted_proxies(["10.0.0.0/8", "127.0.0.1"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn trusted_proxies<I, S>(&mut self, networks: I) -> Result<&mut Self, InvalidCidr>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let networks = networks
+            .into_iter()
+            .map(|net| net.as_ref().parse())
+            .collect::<Result<Vec<Cidr>, _>>()?;
+        self.options.trusted_proxies = TrustedProxies::new(networks);
+        Ok(self)
+    }
+
+    /// Expects every connection to start with a PROXY protocol v1 or v2
+    /// header, as sent by HAProxy or AWS NLB, and uses the source address it
+    /// carries as the peer address. Connections without a valid header
+    /// within the [`header_read_timeout`](Self::header_read_timeout) are
+    /// closed. Only enable this when every client goes through such a proxy.
+    pub fn proxy_protocol(&mut self, enabled: bool) -> &mut Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Listens on `addr` instead of the address and port given to
+    /// [`new`](Self::new). Can be called several times to listen on several
+    /// addresses, and every address a host name resolves to is used.
+    /// `[::]` accepts both IPv6 and IPv4 connections.
+    ///
+    /// ```no_run
+    /// # fn configure(app: &mut server::App) -> std::io::Result<()> {
+    /// app.listen_on("[::]:8080")?.listen_on(("127.0.0.1", 9090))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn listen_on(&mut self, addr: impl ToSocketAddrs) -> io::Result<&mut Self> {
+        self.addresses.extend(addr.to_socket_addrs()?);
+        Ok(self)
+    }
+
+    /// Uses the listening sockets systemd passes through socket activation
+    /// (`LISTEN_FDS`) instead of binding the configured addresses. `name`
+    /// picks the sockets whose `FileDescriptorName=` matches, for services
+    /// with several socket units; `None` takes them all. When the process
+    /// wasn't socket-activated, the configured addresses are bound as usual.
+    pub fn socket_activation(&mut self, name: Option<&str>) -> &mut Self {
+        self.socket_activation = Some(name.map(str::to_string));
+        self
+    }
+
+    /// Upgrades to a new build without refusing connections when the process
+    /// receives SIGUSR2, like nginx and Unicorn.
+    ///
+    /// The executable is started again with the same arguments and handed
+    /// the listening sockets. Once it reports that it is listening,
+    /// [`listen`](Self::listen) stops accepting, drains open connections as
+    /// on shutdown and returns, so the old process can exit. If the new
+    /// process exits first, the old one keeps serving. The new process must
+    /// also enable this option to pick up the sockets. A
+    /// [`unix_socket`](Self::unix_socket) path handed over this way is left in
+    /// place on shutdown and cleaned up on the next start.
+    pub fn upgrade_on_sigusr2(&mut self, enabled: bool) -> &mut Self {
+        self.upgrade_on_sigusr2 = enabled;
+        self
+    }
+
+    /// Also accepts connections on `listener`, bound by the caller, instead of
+    /// the address and port given to [`new`](Self::new).
+    ///
+    /// ```no_run
+    /// # async fn run(mut app: server::App) -> std::io::Result<()> {
+    /// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    /// let addr = listener.local_addr()?;
+    /// app.listener(listener);
+    /// tokio::spawn(async move {
+    ///     let _ = app.listen(server::LogLevel::None).await;
+    /// });
+    /// // connect to `addr`...
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn listener(&mut self, listener: TcpListener) -> &mut Self {
+        self.listeners.push(Listener::Tcp(listener));
+        self
+    }
+
+    /// Listens on a Unix domain socket at `path` instead of the address and
+    /// port given to [`new`](Self::new), with `mode` as its file permissions
+    /// (e.g. `0o660`). Combine with [`listen_on`](Self::listen_on) to serve
+    /// TCP as well. A stale socket left by an earlier run is removed on
+    /// startup, and the socket is removed again on shutdown.
+    pub fn unix_socket(&mut self, path: impl AsRef<Path>, mode: u32) -> &mut Self {
+        self.unix_socket = Some((path.as_ref().to_path_buf(), mode));
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP.
+    pub fn tls(&mut self, config: TlsConfig) -> &mut Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// How long [`listen_with_shutdown`](Self::listen_with_shutdown) waits for
+    /// open connections to finish once shutdown starts.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Writes one access log line per request served.
+    ///
+    /// ```no_run
+    /// # use server::access_log::{AccessLog, AccessLogFormat};
+    /// # fn configure(app: &mut server::App) {
+    /// app.access_log(AccessLog::stdout(AccessLogFormat::Combined));
+    /// # }
+    /// ```
+    pub fn access_log(&mut self, log: AccessLog) -> &mut Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// Serves Prometheus metrics on `path`, e.g. `"/metrics"`: request counts
+    /// by method, route pattern and status, latency histograms, bytes sent,
+    /// and in-flight requests and open connections.
+    pub fn metrics(&mut self, path: &str) -> &mut Self {
+        self.metrics = Some(Arc::new(Metrics::new(path)));
+        self
+    }
+
+    /// Largest request head (request line and header fields) accepted, in
+    /// bytes. Larger ones are answered with 431.
+    pub fn max_header_bytes(&mut self, max: usize) -> &mut Self {
+        self.options.limits.max_header_bytes = max;
+        self
+    }
+
+    /// Largest request body accepted, in bytes. Larger ones are answered with
+    /// 413, before the body is read when its length is declared. Routes can
+    /// raise or lower this with [`Router::configure`](router::Router::configure).
+    pub fn max_body_bytes(&mut self, max: usize) -> &mut Self {
+        self.options.limits.max_body_bytes = max;
+        self
+    }
+
+    /// Longest request target accepted, in bytes. Longer ones are answered
+    /// with 414.
+    pub fn max_uri_length(&mut self, max: usize) -> &mut Self {
+        self.options.limits.max_uri_length = max;
+        self
+    }
+
+    /// Maximum number of connections open at once, and what happens to new
+    /// ones past it. `None` means no limit.
+    pub fn max_connections(&mut self, max: Option<usize>, over_limit: OverLimit) -> &mut Self {
+        self.max_connections = max.map(|max| (max, over_limit));
+        self
+    }
+
+    /// Maximum number of requests served on one connection before it is
+    /// closed. `None` means no limit.
+    pub fn max_requests_per_connection(&mut self, max: Option<usize>) -> &mut Self {
+        self.options.max_requests = max;
+        self
+    }
+
+    /// Maximum number of HTTP/2 streams a client may have open at once on
+    /// one connection. Defaults to 100.
+    pub fn max_concurrent_streams(&mut self, max: u32) -> &mut Self {
+        self.options.max_concurrent_streams = max;
         self
     }
 }
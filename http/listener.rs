@@ -0,0 +1,264 @@
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use crate::connection::ConnectionInfo;
+
+/// A bound socket the server accepts connections from.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// A socket the server created itself has its path removed again when
+    /// the listener is dropped; an inherited one is left alone.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Binds a TCP socket at `addr`.
+    ///
+    /// An unspecified IPv6 address (`[::]`) also accepts IPv4 connections
+    /// unless `v6_only` is set, regardless of the system default.
+    pub(crate) fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+    }
+
+    /// Binds a Unix domain socket at `path` and gives it `mode` permissions.
+    ///
+    /// A socket file left behind by a previous run is removed first, unless
+    /// something is still accepting connections on it.
+    pub(crate) async fn bind_unix(path: &Path, mode: u32) -> io::Result<Self> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(Self::Unix(listener, Some(path.to_path_buf())))
+    }
+
+    /// Takes over a listening socket inherited from the parent process,
+    /// either TCP or Unix.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be open and not owned by anything else in this process. It
+    /// is only closed once it has been checked to be a listening socket, so a
+    /// descriptor that turns out to be something else is left alone.
+    pub(crate) unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        // SAFETY: the caller guarantees `fd` is open.
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let check = SockRef::from(&borrowed);
+        if check.r#type().ok() != Some(Type::STREAM) || !check.is_listener().unwrap_or(false) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file descriptor {} is not a listening stream socket", fd),
+            ));
+        }
+        // SAFETY: the caller guarantees nothing else owns `fd`.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        // Keep it from leaking into processes we spawn.
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        if socket.local_addr()?.is_unix() {
+            Ok(Self::Unix(UnixListener::from_std(socket.into())?, None))
+        } else {
+            Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+        }
+    }
+
+    pub(crate) fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+
+    /// Leaves the socket path in place when dropped, once another process
+    /// has taken over the socket.
+    pub(crate) fn disown(&mut self) {
+        if let Self::Unix(_, path) = self {
+            *path = None;
+        }
+    }
+
+    /// The bound address, for TCP listeners.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            Self::Unix(..) => None,
+        }
+    }
+
+    /// How the listener is reported in logs, e.g. `https://[::]:8443`.
+    pub(crate) fn url(&self, scheme: &str) -> String {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("{}://{}", scheme, addr),
+                Err(_) => format!("{}://-", scheme),
+            },
+            Self::Unix(listener, _) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("{}+unix://{}", scheme, path.display()),
+                    None => format!("{}+unix://-", scheme),
+                },
+                Err(_) => format!("{}+unix://-", scheme),
+            },
+        }
+    }
+
+    pub(crate) fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Stream, ConnectionInfo)>> {
+        match self {
+            Self::Tcp(listener) => listener.poll_accept(cx).map_ok(|(socket, addr)| {
+                let info = ConnectionInfo {
+                    peer_addr: Some(canonical(addr)),
+                    local_addr: socket.local_addr().ok().map(canonical),
+                    tls: None,
+                    unix_socket: false,
+                };
+                (Stream::Tcp(socket), info)
+            }),
+            // Unix peers have no IP address; requests get no client IP
+            // unless a trusted proxy header supplies one.
+            Self::Unix(listener, _) => listener.poll_accept(cx).map_ok(|(socket, _)| {
+                let info = ConnectionInfo {
+                    unix_socket: true,
+                    ..ConnectionInfo::default()
+                };
+                (Stream::Unix(socket), info)
+            }),
+        }
+    }
+}
+
+/// Accepts the next connection on any of `listeners`.
+///
+/// Polling starts one listener further on each call so a busy listener can't
+/// starve the others.
+pub(crate) async fn accept(
+    listeners: &[Listener],
+    next: &mut usize,
+) -> io::Result<(Stream, ConnectionInfo)> {
+    std::future::poll_fn(|cx| {
+        let start = *next;
+        for i in 0..listeners.len() {
+            let index = (start + i) % listeners.len();
+            if let Poll::Ready(accepted) = listeners[index].poll_accept(cx) {
+                *next = index + 1;
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// IPv4 clients of a dual-stack socket appear as `::ffff:a.b.c.d`; report
+/// them as plain IPv4.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Self::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// An accepted connection from either kind of [`Listener`].
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Self::Unix(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(s) => s.is_write_vectored(),
+            Self::Unix(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Self::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
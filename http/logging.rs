@@ -0,0 +1,165 @@
+use std::fmt::{Display, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::LogLevel;
+
+/// Builds the field list for [`Logger::log`]:
+/// `fields!["method" => method, "status" => 200]`.
+macro_rules! fields {
+    ($($key:literal => $value:expr),* $(,)?) => {
+        &[$(($key, &$value as &dyn std::fmt::Display)),*]
+    };
+}
+pub(crate) use fields;
+
+/// How serious a log event is. [`LogLevel`] decides which ones are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Routine events: requests served, connections opened.
+    Info,
+    /// Failures affecting a single request or connection.
+    Error,
+    /// Failures affecting the whole server, and handler panics.
+    Severe,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Error => write!(f, "error"),
+            Self::Severe => write!(f, "severe"),
+        }
+    }
+}
+
+impl LogLevel {
+    pub fn allows(&self, severity: Severity) -> bool {
+        match self {
+            LogLevel::All => true,
+            LogLevel::ErrorsOnly => severity >= Severity::Error,
+            LogLevel::Severe => severity >= Severity::Severe,
+            LogLevel::None => false,
+        }
+    }
+}
+
+/// Writes one logfmt line per event, e.g.
+///
+/// ```text
+/// ts=2025-06-01T12:00:00.000Z level=info msg=request method=GET path=/hello status=200 latency_ms=0.412 peer=127.0.0.1:53422
+/// ```
+///
+/// Info events go to stdout, everything else to stderr.
+#[derive(Clone, Debug)]
+pub struct Logger {
+    level: LogLevel,
+}
+
+impl Logger {
+    pub fn new(level: LogLevel) -> Self {
+        Self { level }
+    }
+
+    pub fn level(&self) -> &LogLevel {
+        &self.level
+    }
+
+    pub fn enabled(&self, severity: Severity) -> bool {
+        self.level.allows(severity)
+    }
+
+    pub fn log(&self, severity: Severity, msg: &str, fields: &[(&str, &dyn Display)]) {
+        if !self.enabled(severity) {
+            return;
+        }
+
+        let mut line = format!(
+            "ts={} level={} msg={}",
+            rfc3339(SystemTime::now()),
+            severity,
+            quote(msg)
+        );
+        for (key, value) in fields {
+            let _ = write!(line, " {}={}", key, quote(&value.to_string()));
+        }
+
+        match severity {
+            Severity::Info => println!("{}", line),
+            _ => eprintln!("{}", line),
+        }
+    }
+
+    pub fn info(&self, msg: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Severity::Info, msg, fields);
+    }
+
+    pub fn error(&self, msg: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Severity::Error, msg, fields);
+    }
+
+    pub fn severe(&self, msg: &str, fields: &[(&str, &dyn Display)]) {
+        self.log(Severity::Severe, msg, fields);
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new(LogLevel::ErrorsOnly)
+    }
+}
+
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != '"' && c != '=' && c != '\\');
+    if plain {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with millisecond precision.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, hour, minute, second) = civil_time(since_epoch.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Splits seconds since the Unix epoch into UTC
+/// `(year, month, day, hour, minute, second)`.
+pub fn civil_time(secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3_600) as u32,
+        (rem % 3_600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
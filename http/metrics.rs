@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::http::method::HttpMethod;
+use crate::http::status::HttpStatusCode;
+use crate::response::Response;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label for requests no route matched (static files and 404s).
+const UNMATCHED: &str = "unmatched";
+
+/// Method label for extension methods no route matched. Clients can send any
+/// token as a method; only those a route accepts get a label of their own.
+const OTHER_METHOD: &str = "other";
+
+#[derive(Debug, Default)]
+struct RouteStats {
+    buckets: [u64; BUCKETS.len()],
+    duration_sum: f64,
+    count: u64,
+    bytes_sent: u64,
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    /// Keyed by `(method, route, status)`.
+    requests: BTreeMap<(String, String, usize), u64>,
+    /// Keyed by `(method, route)`.
+    routes: BTreeMap<(String, String), RouteStats>,
+}
+
+/// Request metrics exposed in the Prometheus text format, enabled with
+/// [`App::metrics`](crate::App::metrics).
+///
+/// Routes are labelled with their registered pattern (`hello/:user`), never
+/// the raw URI, and unrouted extension methods share one label, so the number
+/// of series stays bounded.
+#[derive(Debug)]
+pub struct Metrics {
+    path: String,
+    series: Mutex<Series>,
+    in_flight: AtomicI64,
+    open_connections: AtomicI64,
+}
+
+impl Metrics {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            series: Mutex::new(Series::default()),
+            in_flight: AtomicI64::new(0),
+            open_connections: AtomicI64::new(0),
+        }
+    }
+
+    /// The path the metrics are served on.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether a `GET` or `HEAD` request for `uri` should be answered with
+    /// the metrics.
+    pub fn matches(&self, method: &HttpMethod, uri: &str) -> bool {
+        matches!(method, HttpMethod::Get | HttpMethod::Head)
+            && uri.trim_matches('/') == self.path.trim_matches('/')
+    }
+
+    /// Counts a connection as open until the guard is dropped.
+    pub fn connection_opened(self: &Arc<Self>) -> Gauge {
+        Gauge::enter(Arc::clone(self), |m| &m.open_connections)
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    pub fn request_started(self: &Arc<Self>) -> Gauge {
+        Gauge::enter(Arc::clone(self), |m| &m.in_flight)
+    }
+
+    /// Records a finished request. `route` is the matched pattern, if any.
+    pub fn observe(
+        &self,
+        method: &HttpMethod,
+        route: Option<&str>,
+        status: HttpStatusCode,
+        latency: Duration,
+        bytes_sent: u64,
+    ) {
+        let method = match (method, route) {
+            (HttpMethod::Extension(_), None) => OTHER_METHOD.to_string(),
+            (method, _) => method.to_string(),
+        };
+        let route = route.unwrap_or(UNMATCHED).to_string();
+        let secs = latency.as_secs_f64();
+
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        *series
+            .requests
+            .entry((method.clone(), route.clone(), status.usize()))
+            .or_default() += 1;
+
+        let stats = series.routes.entry((method, route)).or_default();
+        for (bucket, le) in stats.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        stats.duration_sum += secs;
+        stats.count += 1;
+        stats.bytes_sent += bytes_sent;
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests served.",
+        );
+        for ((method, route, status), count) in &series.requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                status,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from reading the request to writing the response.",
+        );
+        for ((method, route), stats) in &series.routes {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            for (bucket, le) in stats.buckets.iter().zip(BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, bucket
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, stats.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, stats.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, stats.count
+            );
+        }
+
+        header(
+            &mut out,
+            "http_response_body_bytes_total",
+            "counter",
+            "Response body bytes sent.",
+        );
+        for ((method, route), stats) in &series.routes {
+            let _ = writeln!(
+                out,
+                "http_response_body_bytes_total{{method=\"{}\",route=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                stats.bytes_sent
+            );
+        }
+
+        header(
+            &mut out,
+            "http_requests_in_flight",
+            "gauge",
+            "Requests currently being handled.",
+        );
+        let _ = writeln!(
+            out,
+            "http_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "http_connections_open",
+            "gauge",
+            "Client connections currently open.",
+        );
+        let _ = writeln!(
+            out,
+            "http_connections_open {}",
+            self.open_connections.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+
+    /// The response served on [`path`](Self::path).
+    pub fn response(&self) -> Response {
+        Response::new()
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(self.render())
+            .build()
+    }
+}
+
+/// Decrements a gauge when dropped, so early returns and panics can't leave
+/// it counting something that is gone.
+#[derive(Debug)]
+pub struct Gauge {
+    metrics: Arc<Metrics>,
+    gauge: fn(&Metrics) -> &AtomicI64,
+}
+
+impl Gauge {
+    fn enter(metrics: Arc<Metrics>, gauge: fn(&Metrics) -> &AtomicI64) -> Self {
+        gauge(&metrics).fetch_add(1, Ordering::Relaxed);
+        Self { metrics, gauge }
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_unrouted_extension_methods_as_other() {
+        let metrics = Metrics::new("metrics");
+        let ok = HttpStatusCode::OK;
+        let latency = Duration::from_millis(1);
+        for token in ["FOO1", "FOO2", "FOO3"] {
+            let method = HttpMethod::extension(token);
+            metrics.observe(&method, None, HttpStatusCode::NotImplemented, latency, 0);
+        }
+        metrics.observe(
+            &HttpMethod::extension("PURGE"),
+            Some("cache"),
+            ok,
+            latency,
+            0,
+        );
+        metrics.observe(&HttpMethod::Get, None, HttpStatusCode::NotFound, latency, 0);
+
+        let out = metrics.render();
+        assert!(
+            out.contains(r#"http_requests_total{method="other",route="unmatched",status="501"} 3"#)
+        );
+        assert!(
+            out.contains(r#"http_requests_total{method="PURGE",route="cache",status="200"} 1"#)
+        );
+        assert!(
+            out.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#)
+        );
+        assert!(!out.contains("FOO"));
+    }
+
+    #[test]
+    fn matches_get_and_head_on_its_path() {
+        let metrics = Metrics::new("/metrics");
+        assert!(metrics.matches(&HttpMethod::Get, "/metrics"));
+        assert!(metrics.matches(&HttpMethod::Head, "/metrics/"));
+        assert!(!metrics.matches(&HttpMethod::Post, "/metrics"));
+        assert!(!metrics.matches(&HttpMethod::Get, "/metrics/x"));
+    }
+}
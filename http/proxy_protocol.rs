@@ -0,0 +1,295 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::http2::Rewind;
+
+/// Longest v1 header allowed by the spec, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Fixed part of a v2 header: signature, version/command, family, length.
+const V2_HEADER_LEN: usize = 16;
+
+/// Addresses carried by a PROXY protocol header, as seen by the proxy.
+///
+/// Both are `None` for `PROXY UNKNOWN`, v2 `LOCAL` connections (health
+/// checks from the proxy itself) and address families other than TCP/UDP
+/// over IPv4/IPv6, in which case the socket's own addresses should be used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `stream`.
+///
+/// Anything read past the header is handed back in the returned stream, so
+/// the TLS handshake or HTTP parsing sees the connection as the client sent
+/// it.
+pub async fn accept<S>(mut stream: S) -> io::Result<(ProxyHeader, Rewind<S>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(V1_MAX_LEN);
+    loop {
+        if let Some((header, len)) = parse(&buf)? {
+            let rest = buf.split_off(len);
+            return Ok((header, Rewind::new(rest, stream)));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Parses a header from the start of `buf`, returning it with its length, or
+/// `None` if more bytes are needed.
+fn parse(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let n = buf.len().min(V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        return parse_v2(buf);
+    }
+    let n = buf.len().min(V1_PREFIX.len());
+    if buf[..n] == V1_PREFIX[..n] {
+        return parse_v1(buf);
+    }
+    Err(invalid("missing PROXY protocol header"))
+}
+
+// PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        return Ok(None);
+    };
+    let len = end + 2;
+    if len > V1_MAX_LEN {
+        return Err(invalid("PROXY v1 header too long"));
+    }
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let mut parts = line.split(' ').skip(1);
+    let header = match parts.next() {
+        Some("UNKNOWN") => ProxyHeader::default(),
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let fields: Vec<&str> = parts.collect();
+            let [src, dst, sport, dport] = fields[..] else {
+                return Err(invalid("invalid PROXY v1 header"));
+            };
+            let ip = |s: &str| -> io::Result<IpAddr> {
+                let ip = s.parse().map_err(|_| invalid("invalid PROXY v1 address"))?;
+                match (family, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                    _ => Err(invalid("PROXY v1 address does not match family")),
+                }
+            };
+            let port = |s: &str| -> io::Result<u16> {
+                // Ports are plain decimal; reject signs and leading zeros.
+                if !s.bytes().all(|b| b.is_ascii_digit())
+                    || s.is_empty()
+                    || (s.len() > 1 && s.starts_with('0'))
+                {
+                    return Err(invalid("invalid PROXY v1 port"));
+                }
+                s.parse().map_err(|_| invalid("invalid PROXY v1 port"))
+            };
+            ProxyHeader {
+                source: Some(SocketAddr::new(ip(src)?, port(sport)?)),
+                destination: Some(SocketAddr::new(ip(dst)?, port(dport)?)),
+            }
+        }
+        _ => return Err(invalid("unsupported PROXY v1 protocol")),
+    };
+    Ok(Some((header, len)))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let addresses = &buf[V2_HEADER_LEN..len];
+    let header = match version_command & 0x0f {
+        // LOCAL: the proxy's own connection, keep the socket addresses.
+        0x0 => ProxyHeader::default(),
+        0x1 => match family >> 4 {
+            0x1 if addresses.len() >= 12 => {
+                let ip = |at: usize| {
+                    IpAddr::V4(Ipv4Addr::new(
+                        addresses[at],
+                        addresses[at + 1],
+                        addresses[at + 2],
+                        addresses[at + 3],
+                    ))
+                };
+                ProxyHeader {
+                    source: Some(SocketAddr::new(ip(0), port(addresses, 8))),
+                    destination: Some(SocketAddr::new(ip(4), port(addresses, 10))),
+                }
+            }
+            0x2 if addresses.len() >= 36 => {
+                let ip = |at: usize| {
+                    let octets: [u8; 16] = addresses[at..at + 16].try_into().unwrap();
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                ProxyHeader {
+                    source: Some(SocketAddr::new(ip(0), port(addresses, 32))),
+                    destination: Some(SocketAddr::new(ip(16), port(addresses, 34))),
+                }
+            }
+            0x1 | 0x2 => return Err(invalid("truncated PROXY v2 addresses")),
+            // AF_UNSPEC and AF_UNIX carry no IP addresses.
+            _ => ProxyHeader::default(),
+        },
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    };
+    Ok(Some((header, len)))
+}
+
+fn port(addresses: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([addresses[at], addresses[at + 1]])
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        let line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET";
+        let (header, len) = parse(line).unwrap().unwrap();
+        assert_eq!(header.source, addr("192.0.2.1:56324"));
+        assert_eq!(header.destination, addr("192.0.2.2:443"));
+        assert_eq!(len, line.len() - 3);
+
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n";
+        let (header, _) = parse(line).unwrap().unwrap();
+        assert_eq!(header.source, addr("[2001:db8::1]:1"));
+
+        let (header, _) = parse(b"PROXY UNKNOWN whatever\r\n").unwrap().unwrap();
+        assert_eq!(header, ProxyHeader::default());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_truncated_header() {
+        let line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        for end in 0..line.len() {
+            assert!(parse(&line[..end]).unwrap().is_none(), "{}", end);
+        }
+        let header = v2(0x1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 1, 187]);
+        for end in 0..header.len() {
+            assert!(parse(&header[..end]).unwrap().is_none(), "{}", end);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_headers_cut_short_by_eof() {
+        let header = v2(0x1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 1, 187]);
+        for input in [&b"PROXY TCP4 192.0.2.1"[..], &header[..20]] {
+            let Err(e) = accept(input).await else {
+                panic!("accepted a truncated header");
+            };
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_v1_headers() {
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        let unterminated = format!("PROXY TCP4 {}", "1".repeat(V1_MAX_LEN));
+        for line in [
+            "PROXY TCP5 192.0.2.1 192.0.2.2 1 2\r\n",
+            "PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 01 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 +1 2\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            "GET / HTTP/1.1\r\n",
+            &long,
+            &unterminated,
+        ] {
+            let e = parse(line.as_bytes()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let header = v2(0x1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 1, 187]);
+        let (parsed, len) = parse(&header).unwrap().unwrap();
+        assert_eq!(parsed.source, addr("192.0.2.1:80"));
+        assert_eq!(parsed.destination, addr("192.0.2.2:443"));
+        assert_eq!(len, header.len());
+
+        let mut addresses = [0u8; 36];
+        addresses[15] = 1;
+        addresses[31] = 2;
+        addresses[32..].copy_from_slice(&[0, 1, 0, 2]);
+        let (parsed, _) = parse(&v2(0x1, 0x21, &addresses)).unwrap().unwrap();
+        assert_eq!(parsed.source, addr("[::1]:1"));
+        assert_eq!(parsed.destination, addr("[::2]:2"));
+
+        // LOCAL and AF_UNIX keep the socket's own addresses; trailing TLVs
+        // are skipped.
+        let (parsed, _) = parse(&v2(0x0, 0x11, &[0; 12])).unwrap().unwrap();
+        assert_eq!(parsed, ProxyHeader::default());
+        let (parsed, _) = parse(&v2(0x1, 0x31, &[0; 216])).unwrap().unwrap();
+        assert_eq!(parsed, ProxyHeader::default());
+    }
+
+    #[test]
+    fn rejects_bad_v2_headers() {
+        let mut wrong_version = v2(0x1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        for header in [
+            wrong_version,
+            v2(0x2, 0x11, &[0; 12]),
+            v2(0x1, 0x11, &[0; 11]),
+            v2(0x1, 0x21, &[0; 35]),
+        ] {
+            let e = parse(&header).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_back_bytes_after_the_header() {
+        let input = b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n\r\n";
+        let (_, mut rest) = accept(&input[..]).await.unwrap();
+        let mut out = Vec::new();
+        rest.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
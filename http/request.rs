@@ -1,10 +1,103 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, fmt::Display, net::IpAddr, str::FromStr};
 
 use crate::{
-    http::{method::HttpMethod, version::HttpVersion},
-    response::Cookie,
+    connection::ConnectionInfo,
+    http::{
+        chunked::{ChunkedDecoder, ChunkedError},
+        method::HttpMethod,
+        status::HttpStatusCode,
+        version::HttpVersion,
+    },
+    response::{Cookie, CookieBuilder},
 };
 
+/// Longest request-target accepted before answering 414, unless
+/// [`App::max_uri_length`](crate::App::max_uri_length) says otherwise.
+pub const MAX_URI_LENGTH: usize = 8 * 1024;
+
+/// Why a request could not be parsed or framed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedEnd,
+    InvalidUtf8,
+    /// The body is not valid UTF-8; [`Request::body`] only holds text.
+    InvalidBodyUtf8,
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidUri,
+    UriTooLong,
+    InvalidVersion,
+    /// A well-formed version other than HTTP/1.x, as `(major, minor)`.
+    UnsupportedVersion(u8, u8),
+    InvalidHeader,
+    /// The request head is larger than the configured limit.
+    HeadersTooLarge,
+    /// A header line continued with leading whitespace (obs-fold).
+    ObsFold,
+    /// HTTP/1.1 requires exactly one `Host` header.
+    InvalidHost,
+    InvalidContentLength,
+    /// Both `Transfer-Encoding` and `Content-Length` were sent.
+    ConflictingLength,
+    /// `Transfer-Encoding` is anything but `chunked` alone; other codings
+    /// are not decoded.
+    UnsupportedTransferEncoding,
+    /// The body is larger than the configured limit.
+    ContentTooLarge,
+    Chunked(ChunkedError),
+}
+
+impl ParseError {
+    /// The status code to answer the client with.
+    pub fn status(&self) -> HttpStatusCode {
+        match self {
+            Self::UriTooLong => HttpStatusCode::URITooLong,
+            Self::HeadersTooLarge => HttpStatusCode::RequestHeaderFieldsTooLarge,
+            Self::ContentTooLarge => HttpStatusCode::ContentTooLarge,
+            Self::UnsupportedVersion(..) => HttpStatusCode::HTTPVersionNotSupported,
+            Self::UnsupportedTransferEncoding => HttpStatusCode::NotImplemented,
+            _ => HttpStatusCode::BadRequest,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "request ended unexpectedly"),
+            Self::InvalidUtf8 => write!(f, "request head is not valid UTF-8"),
+            Self::InvalidBodyUtf8 => write!(f, "request body is not valid UTF-8"),
+            Self::InvalidRequestLine => write!(f, "malformed request line"),
+            Self::InvalidMethod => write!(f, "invalid method"),
+            Self::InvalidUri => write!(f, "invalid request target"),
+            Self::UriTooLong => write!(f, "request target too long"),
+            Self::InvalidVersion => write!(f, "invalid HTTP version"),
+            Self::UnsupportedVersion(major, minor) => {
+                write!(f, "unsupported version HTTP/{}.{}", major, minor)
+            }
+            Self::InvalidHeader => write!(f, "invalid header field"),
+            Self::HeadersTooLarge => write!(f, "request header fields too large"),
+            Self::ObsFold => write!(f, "obsolete header line folding"),
+            Self::InvalidHost => write!(f, "missing or repeated Host header"),
+            Self::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Self::ConflictingLength => {
+                write!(f, "both Transfer-Encoding and Content-Length present")
+            }
+            Self::UnsupportedTransferEncoding => write!(f, "unsupported transfer coding"),
+            Self::ContentTooLarge => write!(f, "request body too large"),
+            Self::Chunked(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ChunkedError> for ParseError {
+    fn from(value: ChunkedError) -> Self {
+        Self::Chunked(value)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Request {
     pub method: HttpMethod,
@@ -13,186 +106,600 @@ pub struct Request {
     pub get_string: String,
     pub headers: HashMap<String, String>,
     pub cookies: HashMap<String, Cookie>,
+    /// The decoded body. Bodies that are not UTF-8 are refused with 400.
     pub body: String,
+    /// The connection the request arrived on: peer and local addresses and
+    /// TLS details.
+    pub connection: ConnectionInfo,
+    /// The client's address: the peer's, or for requests through a trusted
+    /// proxy, the one it reported (see
+    /// [`App::trusted_proxies`](crate::App::trusted_proxies)).
+    pub client_ip: Option<IpAddr>,
 }
 
 impl FromStr for Request {
-    type Err = serde::de::value::Error;
+    type Err = ParseError;
 
+    /// Parses a request head per RFC 9112, followed by an optional body.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut chars = s.chars().peekable();
-        let method: HttpMethod;
-
-        match chars.next().unwrap() {
-            'G' => {
-                method = HttpMethod::Get;
-                chars.next(); // E
-                chars.next(); // T
-                chars.next(); // 
-            }
-            'P' => {
-                match chars.next().unwrap() {
-                    'O' => {
-                        method = HttpMethod::Post;
-                        chars.next(); //S
-                        chars.next(); //T
-                        chars.next(); //
-                    }
-                    'U' => {
-                        method = HttpMethod::Put;
-                        chars.next(); //T
-                        chars.next(); //
-                    }
-                    _ => {
-                        method = HttpMethod::Patch;
-                        chars.next(); //T
-                        chars.next(); //C
-                        chars.next(); //H
-                        chars.next(); //
-                    }
-                }
-            }
-            'D' => {
-                method = HttpMethod::Delete;
-                chars.next(); //E
-                chars.next(); //L
-                chars.next(); //E
-                chars.next(); //T
-                chars.next(); //E
-                chars.next(); // 
+        Self::parse(s, MAX_URI_LENGTH)
+    }
+}
+
+impl Request {
+    /// Like [`from_str`](str::parse), but answering targets longer than
+    /// `max_uri_length` bytes with [`ParseError::UriTooLong`].
+    pub fn parse(s: &str, max_uri_length: usize) -> Result<Self, ParseError> {
+        // Servers should ignore empty lines received before the request-line.
+        let s = s.trim_start_matches("\r\n");
+        let head_end = s.find("\r\n\r\n").ok_or(ParseError::UnexpectedEnd)?;
+        let (head, body) = (&s[..head_end], &s[head_end + 4..]);
+
+        let mut lines = head.split("\r\n");
+        let (method, uri, get_string, version) = parse_request_line(
+            lines.next().ok_or(ParseError::UnexpectedEnd)?,
+            max_uri_length,
+        )?;
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut cookies = HashMap::new();
+        let mut host_count = 0;
+
+        for line in lines {
+            let (name, value) = parse_field_line(line)?;
+
+            if name.eq_ignore_ascii_case("Host") {
+                host_count += 1;
             }
-            _ => {
-                method = HttpMethod::Options; //O
-                chars.next(); //P
-                chars.next(); //T
-                chars.next(); //I
-                chars.next(); //O
-                chars.next(); //N
-                chars.next(); //S
-                chars.next(); // 
+            if name.eq_ignore_ascii_case("Cookie") {
+                parse_cookie_header(value, &mut cookies);
+                continue;
             }
+
+            add_header(&mut headers, name, value);
         }
 
-        let mut uri = String::new();
-        let mut get_string = String::new();
-        let mut uri_done = false;
+        if version == HttpVersion::HTTP_1_1 && host_count != 1 {
+            return Err(ParseError::InvalidHost);
+        }
 
-        while let Some(&c) = chars.peek() {
-            if c == '?' {
-                break;
-            } else if c == ' ' {
-                uri_done = true;
-                break;
+        let mut request = Self {
+            method,
+            uri,
+            get_string,
+            version,
+            headers,
+            cookies,
+            body: body.to_string(),
+            connection: ConnectionInfo::default(),
+            client_ip: None,
+        };
+
+        let chunked = request
+            .header("Transfer-Encoding")
+            .is_some_and(|te| te.trim().eq_ignore_ascii_case("chunked"));
+        if chunked && !request.body.is_empty() {
+            let mut decoder = ChunkedDecoder::new();
+            decoder.decode(request.body.as_bytes())?;
+            if !decoder.is_done() {
+                return Err(ParseError::UnexpectedEnd);
             }
-            uri.push(chars.next().unwrap());
+            let (body, trailers) = decoder.into_parts();
+            request.set_decoded_body(body, trailers)?;
         }
 
-        if !uri_done {
-            chars.next();
-            while let Some(&c) = chars.peek() {
-                if c == ' ' {
-                    break;
-                }
-                get_string.push(chars.next().unwrap());
-            }
+        Ok(request)
+    }
+}
+
+pub(crate) fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_tchar)
+}
+
+// request-line = method SP request-target SP HTTP-version
+fn parse_request_line(
+    line: &str,
+    max_uri_length: usize,
+) -> Result<(HttpMethod, String, String, HttpVersion), ParseError> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::InvalidRequestLine);
+    };
+
+    let method: HttpMethod = method.parse()?;
+    let version = parse_version(version)?;
+
+    if target.len() > max_uri_length {
+        return Err(ParseError::UriTooLong);
+    }
+    let (uri, get_string) = parse_request_target(&method, target)?;
+
+    Ok((method, uri, get_string, version))
+}
+
+// HTTP-version = "HTTP/" DIGIT "." DIGIT
+fn parse_version(version: &str) -> Result<HttpVersion, ParseError> {
+    let digits = version
+        .strip_prefix("HTTP/")
+        .ok_or(ParseError::InvalidVersion)?
+        .as_bytes();
+    let [major, b'.', minor] = digits else {
+        return Err(ParseError::InvalidVersion);
+    };
+    if !major.is_ascii_digit() || !minor.is_ascii_digit() {
+        return Err(ParseError::InvalidVersion);
+    }
+
+    match (major - b'0', minor - b'0') {
+        (1, 0) => Ok(HttpVersion::HTTP_1_0),
+        // Later 1.x minors are compatible with 1.1 by definition.
+        (1, _) => Ok(HttpVersion::HTTP_1_1),
+        (major, minor) => Err(ParseError::UnsupportedVersion(major, minor)),
+    }
+}
+
+/// Splits a request-target into its path and query, validating it against
+/// the form allowed for `method` (RFC 9112 §3.2).
+fn parse_request_target(method: &HttpMethod, target: &str) -> Result<(String, String), ParseError> {
+    if target == "*" {
+        // asterisk-form
+        return match method {
+            HttpMethod::Options => Ok(("*".to_string(), String::new())),
+            _ => Err(ParseError::InvalidUri),
+        };
+    }
+
+    if *method == HttpMethod::Connect {
+        // authority-form: host ":" port, and nothing else for CONNECT
+        let (host, port) = target.rsplit_once(':').ok_or(ParseError::InvalidUri)?;
+        let port_ok = !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit());
+        if host.is_empty()
+            || !port_ok
+            || !target.bytes().all(is_uri_char)
+            || target.contains(['/', '?'])
+        {
+            return Err(ParseError::InvalidUri);
         }
+        return Ok((target.to_string(), String::new()));
+    }
 
-        chars.next(); //  
-        chars.next(); // H
-        chars.next(); // T
-        chars.next(); // T
-        chars.next(); // P
-        chars.next(); // /
+    let path_and_query = if target.starts_with('/') {
+        // origin-form
+        target
+    } else {
+        // absolute-form: scheme "://" authority path-abempty [ "?" query ]
+        let (scheme, rest) = target.split_once("://").ok_or(ParseError::InvalidUri)?;
+        let scheme_ok = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"+-.".contains(&c));
+        if !scheme_ok {
+            return Err(ParseError::InvalidUri);
+        }
+        let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+        let authority = &rest[..path_start];
+        if authority.is_empty() || !authority.bytes().all(is_uri_char) {
+            return Err(ParseError::InvalidUri);
+        }
+        match &rest[path_start..] {
+            "" => "/",
+            p => p,
+        }
+    };
+
+    if !path_and_query.bytes().all(is_uri_char) || !valid_percent_encoding(path_and_query) {
+        return Err(ParseError::InvalidUri);
+    }
 
-        let version: HttpVersion;
-        match chars.next().unwrap() {
-            '0' => {
-                version = HttpVersion::HTTP_0_9;
-                chars.next(); //.
-                chars.next(); //9
+    let (path, query) = path_and_query
+        .split_once('?')
+        .unwrap_or((path_and_query, ""));
+    let path = if path.is_empty() { "/" } else { path };
+    let path = remove_dot_segments(path).ok_or(ParseError::InvalidUri)?;
+    Ok((path, query.to_string()))
+}
+
+/// Resolves the `.` and `..` segments of an absolute path (RFC 3986
+/// §5.2.4), so routes and static files only ever see the path they name.
+/// Percent-encoded dots count too. Returns `None` when a `..` would climb
+/// above the root.
+pub(crate) fn remove_dot_segments(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let mut out: Vec<&str> = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i + 1 == segments.len();
+        match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+            "." => {}
+            ".." => {
+                out.pop()?;
             }
-            '1' => {
-                chars.next();
-                match chars.next().unwrap() {
-                    '0' => {
-                        version = HttpVersion::HTTP_1_0;
-                    }
-                    _ => {
-                        version = HttpVersion::HTTP_1_1;
-                    }
-                }
+            _ => {
+                out.push(segment);
+                continue;
             }
-            '2' => {
-                version = HttpVersion::HTTP_2_0;
-                chars.next(); //.
-                chars.next(); //0
+        }
+        // `/a/b/..` names the directory `/a/`, trailing slash included.
+        if last {
+            out.push("");
+        }
+    }
+    Some(format!("/{}", out.join("/")))
+}
+
+// pchar / "/" / "?" — unreserved, pct-encoded, sub-delims, ":" and "@"
+fn is_uri_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=:@/?[]".contains(&c)
+}
+
+fn valid_percent_encoding(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            match bytes.get(i + 1..i + 3) {
+                Some([a, b]) if a.is_ascii_hexdigit() && b.is_ascii_hexdigit() => i += 3,
+                _ => return false,
             }
-            _ => {
-                version = HttpVersion::HTTP_3_0;
-                chars.next(); //.
-                chars.next(); //0
+        } else {
+            i += 1;
+        }
+    }
+    true
+}
+
+// field-line = field-name ":" OWS field-value OWS
+pub(crate) fn parse_field_line(line: &str) -> Result<(&str, &str), ParseError> {
+    // Line folding (obs-fold) is deprecated; reject it rather than guess.
+    if line.starts_with([' ', '\t']) {
+        return Err(ParseError::ObsFold);
+    }
+
+    let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+    // No whitespace is allowed between the field name and the colon.
+    if !is_token(name) {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    let value = value.trim_matches([' ', '\t']);
+    let value_ok = value
+        .bytes()
+        .all(|c| c == b' ' || c == b'\t' || c >= 0x21 && c != 0x7f);
+    if !value_ok {
+        return Err(ParseError::InvalidHeader);
+    }
+
+    Ok((name, value))
+}
+
+/// Adds a field to `headers`. Repeated fields are combined into one
+/// comma-separated list under the name first seen, whatever its case.
+fn add_header(headers: &mut HashMap<String, String>, name: &str, value: &str) {
+    let existing = headers
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case(name))
+        .cloned();
+    match existing {
+        Some(key) => {
+            let combined = headers.get_mut(&key).unwrap();
+            combined.push_str(", ");
+            combined.push_str(value);
+        }
+        None => {
+            headers.insert(name.to_string(), value.to_string());
+        }
+    }
+}
+
+/// Fields a sender must not put in a trailer section (RFC 9110 §6.5.1):
+/// message framing, routing, request modifiers, authentication, content
+/// processing and connection control. They are dropped, not merged.
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "Authorization",
+    "Cache-Control",
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Cookie",
+    "Expect",
+    "Host",
+    "If-Match",
+    "If-Modified-Since",
+    "If-None-Match",
+    "If-Range",
+    "If-Unmodified-Since",
+    "Keep-Alive",
+    "Max-Forwards",
+    "Pragma",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "Range",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Adds the `name=value` pairs of a `Cookie` header to `cookies`.
+pub(crate) fn parse_cookie_header(value: &str, cookies: &mut HashMap<String, Cookie>) {
+    for pair in value.split(';') {
+        if let Some((name, value)) = pair.trim().split_once('=') {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
             }
+            cookies.insert(
+                name.to_string(),
+                CookieBuilder::new(name, value.trim().trim_matches('"')).build(),
+            );
         }
-        chars.next(); //\r
-        chars.next(); //\n
+    }
+}
 
-        let mut headers = HashMap::new();
-        let mut cookies = HashMap::new();
+impl Request {
+    /// Looks up a header by name, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
 
-        let mut buffer1 = String::new();
-        let mut buffer2 = String::new();
-
-        let mut state = false; // or use enum
-
-        while let Some(c) = chars.next() {
-            match c {
-                ':' if !state => {
-                    state = true;
-                    while chars.peek() == Some(&' ') {
-                        chars.next();
-                    }
-                }
-                '\r' => {
-                    if chars.peek() == Some(&'\n') {
-                        chars.next();
-
-                        if !buffer1.is_empty() {
-                            match buffer1.as_str() {
-                                "Cookie" => {
-                                    cookies.insert(buffer2.clone(), Cookie::from(buffer1.trim()));
-                                }
-                                _ => {
-                                    headers.insert(buffer2.clone(), buffer1.trim().to_string());
-                                }
-                            }
-                            buffer1.clear();
-                            buffer2.clear();
-                            state = false;
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                c => {
-                    if state {
-                        buffer1.push(c);
-                    } else {
-                        buffer2.push(c);
-                    }
-                }
+    /// Replaces a chunked body with its decoded form. `Transfer-Encoding` is
+    /// removed, `Content-Length` is set to the decoded length and trailer
+    /// fields are merged into the headers, except those not allowed in
+    /// trailers. A body that is not UTF-8 is refused.
+    pub(crate) fn set_decoded_body(
+        &mut self,
+        body: Vec<u8>,
+        trailers: Vec<(String, String)>,
+    ) -> Result<(), ParseError> {
+        let body = String::from_utf8(body).map_err(|_| ParseError::InvalidBodyUtf8)?;
+        self.headers
+            .retain(|k, _| !k.eq_ignore_ascii_case("Transfer-Encoding"));
+        self.headers
+            .insert("Content-Length".into(), body.len().to_string());
+        for (name, value) in trailers {
+            if !FORBIDDEN_TRAILERS
+                .iter()
+                .any(|f| f.eq_ignore_ascii_case(&name))
+            {
+                add_header(&mut self.headers, &name, &value);
             }
         }
+        self.body = body;
+        Ok(())
+    }
+}
 
-        let body: String = chars.collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(Self {
-            method,
-            uri,
-            get_string,
-            version,
-            headers,
-            cookies,
-            body,
-        })
+    fn parse(head: &str) -> Result<Request, ParseError> {
+        Request::parse(head, MAX_URI_LENGTH)
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let request =
+            parse("GET /a/b?x=1 HTTP/1.1\r\nHost: example.com\r\nCookie: a=1; b=2\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.method, HttpMethod::Get);
+        assert_eq!(request.version, HttpVersion::HTTP_1_1);
+        assert_eq!(request.uri, "/a/b");
+        assert_eq!(request.get_string, "x=1");
+        assert_eq!(
+            request.header("host").map(String::as_str),
+            Some("example.com")
+        );
+        assert_eq!(request.cookies.len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_methods() {
+        for line in ["G@T / HTTP/1.1", "GET\t/ HTTP/1.1", " / HTTP/1.1"] {
+            let head = format!("{}\r\nHost: test\r\n\r\n", line);
+            assert!(
+                matches!(
+                    parse(&head),
+                    Err(ParseError::InvalidMethod | ParseError::InvalidRequestLine)
+                ),
+                "{:?}",
+                line
+            );
+        }
+        assert_eq!(
+            parse("G@T / HTTP/1.1\r\nHost: test\r\n\r\n").unwrap_err(),
+            ParseError::InvalidMethod
+        );
+    }
+
+    #[test]
+    fn rejects_bad_versions() {
+        for version in ["HTTP/1", "HTTP/1.10", "http/1.1", "HTTP/x.1", "HTTP/1.1 "] {
+            let head = format!("GET / {}\r\nHost: test\r\n\r\n", version);
+            assert!(parse(&head).is_err(), "{:?}", version);
+        }
+        assert_eq!(
+            parse("GET / HTTP/1\r\nHost: test\r\n\r\n").unwrap_err(),
+            ParseError::InvalidVersion
+        );
+        assert_eq!(
+            parse("GET / HTTP/2.0\r\nHost: test\r\n\r\n").unwrap_err(),
+            ParseError::UnsupportedVersion(2, 0)
+        );
+        // Later 1.x minors are served as 1.1.
+        let request = parse("GET / HTTP/1.7\r\nHost: test\r\n\r\n").unwrap();
+        assert_eq!(request.version, HttpVersion::HTTP_1_1);
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        for (target, path) in [
+            ("/a/b/../c", "/a/c"),
+            ("/a/./b", "/a/b"),
+            ("/a/b/..", "/a/"),
+            ("/a/.", "/a/"),
+            ("/a/%2E%2e/b", "/b"),
+            ("/./", "/"),
+            ("http://example.com/a/../b?x=..", "/b"),
+        ] {
+            let head = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", target);
+            assert_eq!(parse(&head).unwrap().uri, path, "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_above_the_root() {
+        for target in ["/..", "/assets/../../Cargo.toml", "/a/%2e%2e/..", "/../a"] {
+            let head = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", target);
+            assert_eq!(
+                parse(&head).unwrap_err(),
+                ParseError::InvalidUri,
+                "{:?}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_obs_fold() {
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: test\r\nX-Long: a\r\n b\r\n\r\n").unwrap_err(),
+            ParseError::ObsFold
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: test\r\nX-Long: a\r\n\tb\r\n\r\n").unwrap_err(),
+            ParseError::ObsFold
+        );
+    }
+
+    #[test]
+    fn rejects_bad_header_lines() {
+        for line in [
+            "X-Bad : value",
+            "No colon",
+            ": empty name",
+            "X-Ctl: a\u{7f}b",
+        ] {
+            let head = format!("GET / HTTP/1.1\r\nHost: test\r\n{}\r\n\r\n", line);
+            assert_eq!(
+                parse(&head).unwrap_err(),
+                ParseError::InvalidHeader,
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn requires_exactly_one_host() {
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n").unwrap_err(),
+            ParseError::InvalidHost
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n").unwrap_err(),
+            ParseError::InvalidHost
+        );
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn merges_repeated_headers() {
+        let request =
+            parse("GET / HTTP/1.1\r\nHost: test\r\nAccept: a\r\naccept: b\r\n\r\n").unwrap();
+        assert_eq!(request.header("Accept").map(String::as_str), Some("a, b"));
+        assert_eq!(request.headers.len(), 2);
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let request = parse(
+            "POST / HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.body, "hello");
+        assert_eq!(
+            request.header("Content-Length").map(String::as_str),
+            Some("5")
+        );
+        assert_eq!(request.header("X-Sum").map(String::as_str), Some("1"));
+        assert!(request.header("Transfer-Encoding").is_none());
+
+        let truncated =
+            parse("POST / HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel");
+        assert!(truncated.is_err());
+    }
+
+    fn chunked_request() -> Request {
+        Request::parse(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\nX-Tag: a\r\nTransfer-Encoding: chunked\r\n\r\n",
+            8192,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn merges_trailers_by_case_insensitive_name() {
+        let mut request = chunked_request();
+        request
+            .set_decoded_body(b"hi".to_vec(), vec![("x-tag".into(), "b".into())])
+            .unwrap();
+        assert_eq!(
+            request.headers.get("X-Tag").map(String::as_str),
+            Some("a, b")
+        );
+        assert!(!request.headers.contains_key("x-tag"));
+        assert_eq!(request.body, "hi");
+    }
+
+    #[test]
+    fn drops_forbidden_trailers() {
+        let mut request = chunked_request();
+        request
+            .set_decoded_body(
+                b"hi".to_vec(),
+                vec![
+                    ("host".into(), "evil.example".into()),
+                    ("content-length".into(), "100".into()),
+                    ("Transfer-Encoding".into(), "chunked".into()),
+                    ("Authorization".into(), "Basic Zm9v".into()),
+                    ("X-Checksum".into(), "abc".into()),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            request.headers.get("Host").map(String::as_str),
+            Some("example.com")
+        );
+        assert_eq!(
+            request.headers.get("Content-Length").map(String::as_str),
+            Some("2")
+        );
+        assert!(
+            request
+                .headers
+                .keys()
+                .all(|k| !k.eq_ignore_ascii_case("Transfer-Encoding"))
+        );
+        assert!(
+            request
+                .headers
+                .keys()
+                .all(|k| !k.eq_ignore_ascii_case("Authorization"))
+        );
+        assert_eq!(
+            request.headers.get("X-Checksum").map(String::as_str),
+            Some("abc")
+        );
     }
 }
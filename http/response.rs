@@ -1,15 +1,45 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, io, pin::Pin};
 
+use futures::{Stream, StreamExt};
 use serde::Serialize;
 use serde_json::value::Serializer;
+use tokio::io::AsyncRead;
 
-use crate::http::status::HttpStatusCode;
+use crate::http::{status::HttpStatusCode, version::HttpVersion};
 
 pub struct Response {
     status: HttpStatusCode,
     headers: HashMap<String, String>,
     cookies: HashMap<String, String>,
-    body: Vec<u8>,
+    body: Body,
+}
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Vec<u8>>> + Send>>;
+
+/// A response body, either held in memory or produced while it is written.
+///
+/// Streamed bodies have no `Content-Length`; they are sent with chunked
+/// encoding on HTTP/1.1 and delimited by closing the connection on HTTP/1.0.
+pub enum Body {
+    Full(Vec<u8>),
+    Stream(BodyStream),
+    Reader(Pin<Box<dyn AsyncRead + Send>>),
+}
+
+impl Body {
+    pub fn is_streaming(&self) -> bool {
+        !matches!(self, Body::Full(_))
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Full(body) => write!(f, "Body::Full({} bytes)", body.len()),
+            Body::Stream(_) => write!(f, "Body::Stream(<stream>)"),
+            Body::Reader(_) => write!(f, "Body::Reader(<reader>)"),
+        }
+    }
 }
 
 impl Response {
@@ -19,9 +49,60 @@ impl Response {
             status: HttpStatusCode::OK,
             headers: HashMap::from([("Content-Length".into(), "0".into())]),
             cookies: HashMap::new(),
-            body: Vec::new(),
+            body: Body::Full(Vec::new()),
         }
     }
+
+    pub fn status(&self) -> HttpStatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HashMap<String, String> {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HashMap<String, String> {
+        &mut self.headers
+    }
+
+    /// Looks up a header by name, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn cookies(&self) -> &HashMap<String, String> {
+        &self.cookies
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Drops the body but keeps the headers describing it, as the answer to
+    /// a `HEAD` request needs.
+    pub(crate) fn strip_body(&mut self) {
+        self.body = Body::Full(Vec::new());
+    }
+
+    pub(crate) fn into_body(self) -> Body {
+        self.body
+    }
+
+    /// Status line and header block, including the blank line that ends it.
+    pub(crate) fn head(&self, version: HttpVersion) -> String {
+        let mut head = format!("{} {}\r\n", version, self.status);
+        for (k, v) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        for (k, v) in &self.cookies {
+            head.push_str(&format!("Set-Cookie: {}={}\r\n", k, v));
+        }
+        head.push_str("\r\n");
+        head
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -66,10 +147,8 @@ impl From<&str> for Cookie {
                     "path" => cookie.path = value.map(str::to_string),
                     "domain" => cookie.domain = value.map(str::to_string),
                     "max-age" => {
-                        if let Some(v) = value {
-                            if let Ok(secs) = v.parse() {
-                                cookie.max_age = Some(secs);
-                            }
+                        if let Some(Ok(secs)) = value.map(str::parse) {
+                            cookie.max_age = Some(secs);
                         }
                     }
                     "samesite" => cookie.same_site = value.map(str::to_string),
@@ -157,45 +236,25 @@ impl Display for Cookie {
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.cookies.is_empty() {
-            write!(
-                f,
-                "{}\r\n{}\r\n\r\n{}",
-                self.status,
-                self.headers
-                    .iter()
-                    .map(|(a, b)| { format!("{}: {}", a, b) })
-                    .collect::<Vec<String>>()
-                    .join("\r\n"),
-                String::from_utf8_lossy(self.body.as_slice())
-            )
-        } else {
-            write!(
-                f,
-                "{}\r\n{}Set-Cookie: {}\r\n\r\n{}",
-                self.status,
-                self.headers
-                    .iter()
-                    .map(|(a, b)| { format!("{}: {}", a, b) })
-                    .collect::<Vec<String>>()
-                    .join("\r\n"),
-                self.cookies
-                    .iter()
-                    .map(|(a, b)| format!("{}={}", a, b))
-                    .collect::<Vec<String>>()
-                    .join("; "),
-                String::from_utf8_lossy(self.body.as_slice())
-            )
+        write!(f, "{}\r\n", self.status)?;
+        for (k, v) in &self.headers {
+            write!(f, "{}: {}\r\n", k, v)?;
+        }
+        for (k, v) in &self.cookies {
+            write!(f, "Set-Cookie: {}={}\r\n", k, v)?;
+        }
+        match &self.body {
+            Body::Full(body) => write!(f, "\r\n{}", String::from_utf8_lossy(body)),
+            body => write!(f, "\r\n<{:?}>", body),
         }
     }
 }
 
-#[derive(Clone)]
 pub struct ReponseBuilder {
     status: HttpStatusCode,
     headers: HashMap<String, String>,
     cookies: HashMap<String, Cookie>,
-    body: Vec<u8>,
+    body: Body,
 }
 
 impl ReponseBuilder {
@@ -244,20 +303,41 @@ impl ReponseBuilder {
     }
 
     pub fn body<T: ToString>(mut self, body: T) -> Self {
-        self.body = Vec::from(body.to_string().as_bytes());
+        let body = Vec::from(body.to_string().as_bytes());
         self.headers
             .insert(String::from("Content-Type"), "text/plain".to_string());
-        self.headers.insert(
-            String::from("Content-Length"),
-            format!("{}", self.body.len()),
-        );
+        self.headers
+            .insert(String::from("Content-Length"), format!("{}", body.len()));
+        self.body = Body::Full(body);
         self
     }
 
     pub fn body_raw(mut self, body: Vec<u8>) -> Self {
         self.headers
             .insert("Content-Length".into(), format!("{}", body.len()));
-        self.body = body;
+        self.body = Body::Full(body);
+        self
+    }
+
+    /// Streams the body from `stream` as it produces chunks, instead of
+    /// holding it all in memory.
+    pub fn stream<S, T>(mut self, stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<T>> + Send + 'static,
+        T: Into<Vec<u8>>,
+    {
+        self.headers.remove("Content-Length");
+        self.body = Body::Stream(Box::pin(stream.map(|chunk| chunk.map(Into::into))));
+        self
+    }
+
+    /// Streams the body from `reader` until it reaches EOF.
+    pub fn reader<R>(mut self, reader: R) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        self.headers.remove("Content-Length");
+        self.body = Body::Reader(Box::pin(reader));
         self
     }
 
@@ -268,16 +348,10 @@ impl ReponseBuilder {
     }
 
     pub fn json<T: Serialize>(mut self, body: T) -> Self {
-        unsafe {
-            self.body = body
-                .serialize(Serializer)
-                .unwrap()
-                .to_string()
-                .as_mut_vec()
-                .to_owned();
-        }
+        let body = body.serialize(Serializer).unwrap().to_string().into_bytes();
         self.headers
-            .insert("Content-Length".into(), format!("{}", self.body.len()));
+            .insert("Content-Length".into(), format!("{}", body.len()));
+        self.body = Body::Full(body);
         self.headers
             .insert("Content-Type".into(), String::from("application/json"));
         self
@@ -1,13 +1,17 @@
 use crate::request::Request;
 use crate::{database::DBConnection, http::method::HttpMethod, response::Response};
+use futures::FutureExt;
 use paste::paste;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
 use std::future::Future;
 use std::io::Read;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[allow(clippy::type_complexity)]
 pub enum Route {
@@ -120,11 +124,19 @@ impl fmt::Debug for Route {
     }
 }
 
+/// A registered handler together with the pattern it was registered under,
+/// e.g. `hello/:user`.
+#[derive(Debug)]
+pub struct Endpoint {
+    pub pattern: String,
+    pub route: Route,
+}
+
 #[derive(Debug)]
 pub struct RouteTree {
     children: HashMap<String, RouteTree>,
     param_children: HashMap<String, RouteTree>,
-    handler: Option<Route>,
+    handler: Option<Endpoint>,
 }
 
 impl RouteTree {
@@ -132,7 +144,7 @@ impl RouteTree {
         &'a self,
         segments: &[&str],
         params: &mut HashMap<String, String>,
-    ) -> Option<&'a Route> {
+    ) -> Option<&'a Endpoint> {
         if segments.is_empty() {
             return self.handler.as_ref();
         }
@@ -160,7 +172,7 @@ impl RouteTree {
         None
     }
 
-    fn add(&mut self, uri: String, handler: Route) {
+    fn add(&mut self, uri: String, handler: Endpoint) {
         // If index
         if &uri == "/" {
             self.handler = Some(handler);
@@ -230,10 +242,97 @@ impl RouteTree {
     }
 }
 
+/// Details of a handler panic, passed to [`Router::on_panic`].
+#[derive(Clone, Debug)]
+pub struct HandlerPanic {
+    pub method: HttpMethod,
+    pub uri: String,
+    /// The pattern of the route that panicked, e.g. `hello/:user`.
+    pub route: String,
+    pub message: String,
+}
+
+/// The outcome of [`Router::dispatch`].
+pub struct Dispatch {
+    pub response: Response,
+    /// Pattern of the matched route; `None` for static files and 404s.
+    pub route: Option<String>,
+    pub panic: Option<HandlerPanic>,
+}
+
+#[allow(clippy::type_complexity)]
+pub struct PanicHandler(Box<dyn Fn(&HandlerPanic) -> Response + Send + Sync + 'static>);
+
+impl fmt::Debug for PanicHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PanicHandler(<function>)")
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub struct ExpectHandler(Box<dyn Fn(&Request) -> Option<Response> + Send + Sync + 'static>);
+
+impl fmt::Debug for ExpectHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ExpectHandler(<function>)")
+    }
+}
+
+/// Per-route overrides of the server-wide limits and timeouts, set with
+/// [`Router::configure`]. Unset fields use the [`App`](crate::App) value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RouteConfig {
+    pub max_header_bytes: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    pub body_read_timeout: Option<Duration>,
+    pub handler_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+}
+
+impl RouteConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_header_bytes(mut self, max: usize) -> Self {
+        self.max_header_bytes = Some(max);
+        self
+    }
+
+    pub fn max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = Some(max);
+        self
+    }
+
+    pub fn max_uri_length(mut self, max: usize) -> Self {
+        self.max_uri_length = Some(max);
+        self
+    }
+
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.body_read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+}
+
 #[derive(Debug)]
 pub struct Router {
     routes: HashMap<HttpMethod, RouteTree>,
+    route_configs: HashMap<String, RouteConfig>,
     static_routes: HashMap<String, String>,
+    panic_handler: Option<PanicHandler>,
+    expect_handler: Option<ExpectHandler>,
 }
 
 impl Default for Router {
@@ -323,22 +422,14 @@ macro_rules! route_method_impl {
             where
                 F: IntoRouteHandler<Args>,
             {
-                self.routes
-                    .get_mut(&crate::http::method::HttpMethod::$variant)
-                    .unwrap()
-                    .add(uri.to_string(), handler.into_route());
-                self
+                self.r_route(crate::http::method::HttpMethod::$variant, uri, handler)
             }
 
-            pub fn $name<F, Args>(mut self, uri: &str, handler: F) -> Self
+            pub fn $name<F, Args>(self, uri: &str, handler: F) -> Self
             where
                 F: IntoRouteHandler<Args>,
             {
-                self.routes
-                    .get_mut(&crate::http::method::HttpMethod::$variant)
-                    .unwrap()
-                    .add(uri.to_string(), handler.into_route());
-                self
+                self.route(crate::http::method::HttpMethod::$variant, uri, handler)
             }
         }
     };
@@ -347,18 +438,103 @@ macro_rules! route_method_impl {
 impl Router {
     pub fn new() -> Self {
         Self {
-            routes: HashMap::from([
-                (HttpMethod::Get, RouteTree::new()),
-                (HttpMethod::Post, RouteTree::new()),
-                (HttpMethod::Patch, RouteTree::new()),
-                (HttpMethod::Put, RouteTree::new()),
-                (HttpMethod::Delete, RouteTree::new()),
-                (HttpMethod::Options, RouteTree::new()),
-            ]),
+            routes: HashMap::new(),
+            route_configs: HashMap::new(),
             static_routes: HashMap::new(),
+            panic_handler: None,
+            expect_handler: None,
         }
     }
 
+    /// Builds the response sent when a handler panics. The default is an empty
+    /// 500. Either way the connection is closed after the response.
+    pub fn on_panic<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HandlerPanic) -> Response + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(PanicHandler(Box::new(handler)));
+        self
+    }
+
+    pub fn r_on_panic<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&HandlerPanic) -> Response + Send + Sync + 'static,
+    {
+        self.panic_handler = Some(PanicHandler(Box::new(handler)));
+        self
+    }
+
+    /// Decides whether to accept the body of a request sent with
+    /// `Expect: 100-continue`. The handler sees the request head only; the
+    /// body has not been read yet. Returning a response, typically 417 or
+    /// 413, refuses the upload and closes the connection. Returning `None`
+    /// sends `100 Continue` and the request is routed as usual.
+    pub fn on_expect<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        self.expect_handler = Some(ExpectHandler(Box::new(handler)));
+        self
+    }
+
+    pub fn r_on_expect<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        self.expect_handler = Some(ExpectHandler(Box::new(handler)));
+        self
+    }
+
+    /// Overrides limits and timeouts for the routes registered under `uri`,
+    /// whatever their method, e.g. to accept large, slow bodies on an upload
+    /// endpoint.
+    ///
+    /// ```no_run
+    /// # use server::router::{RouteConfig, Router};
+    /// # use server::response::Response;
+    /// let router = Router::new()
+    ///     .post("upload", async || Response::new().build())
+    ///     .configure("upload", RouteConfig::new().max_body_bytes(1 << 30));
+    /// ```
+    pub fn configure(mut self, uri: &str, config: RouteConfig) -> Self {
+        self.route_configs.insert(uri.to_string(), config);
+        self
+    }
+
+    pub fn r_configure(&mut self, uri: &str, config: RouteConfig) -> &mut Self {
+        self.route_configs.insert(uri.to_string(), config);
+        self
+    }
+
+    /// Pattern of the route `request` would be dispatched to, if any.
+    pub fn matched_route(&self, request: &Request) -> Option<&str> {
+        let method = match request.method {
+            HttpMethod::Head if self.find(&HttpMethod::Head, &request.uri).is_none() => {
+                HttpMethod::Get
+            }
+            ref method => method.clone(),
+        };
+        let (endpoint, _) = self.find(&method, &request.uri)?;
+        Some(&endpoint.pattern)
+    }
+
+    /// The overrides for the route `request` would be dispatched to.
+    pub fn route_config(&self, request: &Request) -> Option<&RouteConfig> {
+        self.route_configs.get(self.matched_route(request)?)
+    }
+
+    /// Every override registered with [`configure`](Self::configure).
+    pub fn route_configs(&self) -> impl Iterator<Item = &RouteConfig> {
+        self.route_configs.values()
+    }
+
+    /// Runs the [`on_expect`](Self::on_expect) handler, if any.
+    pub fn check_expect(&self, request: &Request) -> Option<Response> {
+        self.expect_handler
+            .as_ref()
+            .and_then(|ExpectHandler(handler)| handler(request))
+    }
+
     pub fn serve_dir(mut self, uri: &str, dir: &str) -> Self {
         self.static_routes.insert(
             if uri.starts_with("/") {
@@ -380,54 +556,361 @@ impl Router {
         self
     }
 
+    /// Registers `handler` for `method` on `uri`. Use this for methods
+    /// without a shorthand, such as `HttpMethod::extension("PROPFIND")`.
+    ///
+    /// `CONNECT` requests have `host:port` as their uri, so their handlers are
+    /// usually registered under a parameter such as `:target`.
+    pub fn route<F, Args>(mut self, method: HttpMethod, uri: &str, handler: F) -> Self
+    where
+        F: IntoRouteHandler<Args>,
+    {
+        self.r_route(method, uri, handler);
+        self
+    }
+
+    pub fn r_route<F, Args>(&mut self, method: HttpMethod, uri: &str, handler: F) -> &mut Self
+    where
+        F: IntoRouteHandler<Args>,
+    {
+        self.routes
+            .entry(method)
+            .or_insert_with(RouteTree::new)
+            .add(
+                uri.to_string(),
+                Endpoint {
+                    pattern: uri.to_string(),
+                    route: handler.into_route(),
+                },
+            );
+        self
+    }
+
     route_method_impl!(get, Get);
+    route_method_impl!(head, Head);
     route_method_impl!(post, Post);
     route_method_impl!(patch, Patch);
     route_method_impl!(put, Put);
     route_method_impl!(delete, Delete);
     route_method_impl!(options, Options);
+    route_method_impl!(trace, Trace);
+    route_method_impl!(connect, Connect);
 
     pub async fn handle(&self, request: Request) -> Response {
-        let route_and_params = {
-            let root = self.routes.get(&request.method).unwrap();
-            let segments: Vec<&str> = request.uri.split('/').filter(|s| !s.is_empty()).collect();
-            let mut params = HashMap::new();
-            root.find_match(&segments, &mut params)
-                .map(|handler| (handler, params))
-        };
-        if let Some((r, p)) = route_and_params {
-            r.run(request, p, &mut Arc::new(DBConnection::connect()))
-                .await
+        self.dispatch(request).await.response
+    }
+
+    /// Like [`handle`](Self::handle), but also reports which route served the
+    /// request and whether its handler panicked.
+    ///
+    /// `HEAD` requests without a handler of their own run the `GET` handler.
+    /// Either way the body is dropped from the response and its headers,
+    /// including `Content-Length`, are kept.
+    pub async fn dispatch(&self, request: Request) -> Dispatch {
+        if request.method != HttpMethod::Head {
+            return self.dispatch_method(request.method.clone(), request).await;
+        }
+
+        let method = if self.find(&HttpMethod::Head, &request.uri).is_some() {
+            HttpMethod::Head
         } else {
-            for i in &self.static_routes {
-                if request.uri.starts_with(i.0) {
-                    let filename = format!(
-                        ".{}/{}",
-                        i.1,
-                        request.uri.chars().skip(i.0.len()).collect::<String>()
-                    );
-
-                    if let Ok(mut file) = File::open(&filename) {
-                        let mut buf = Vec::new();
-                        if file.read_to_end(&mut buf).is_err() {
-                            return Response::new()
-                                .status(crate::http::status::HttpStatusCode::InternalServerError)
-                                .build();
-                        }
-                        let mime_type = mime_guess::from_path(filename)
-                            .first_or_text_plain()
-                            .to_string();
-                        return Response::new()
-                            .body_raw(buf)
-                            .header("Content-Type", mime_type.as_str())
-                            .build();
-                    }
+            HttpMethod::Get
+        };
+        let mut dispatch = self.dispatch_method(method, request).await;
+        dispatch.response.strip_body();
+        dispatch
+    }
+
+    /// Finds the endpoint registered for `method` on `uri`, with the values
+    /// of its parameters.
+    fn find(&self, method: &HttpMethod, uri: &str) -> Option<(&Endpoint, HashMap<String, String>)> {
+        let root = self.routes.get(method)?;
+        let segments: Vec<&str> = uri.split('/').filter(|s| !s.is_empty()).collect();
+        let mut params = HashMap::new();
+        root.find_match(&segments, &mut params)
+            .map(|endpoint| (endpoint, params))
+    }
+
+    async fn dispatch_method(&self, method: HttpMethod, request: Request) -> Dispatch {
+        let Some((endpoint, p)) = self.find(&method, &request.uri) else {
+            return Dispatch {
+                response: self.unmatched(&method, &request.uri),
+                route: None,
+                panic: None,
+            };
+        };
+
+        let method = request.method.clone();
+        let uri = request.uri.clone();
+        let mut conn = Arc::new(DBConnection::connect());
+        let run = endpoint.route.run(request, p, &mut conn);
+
+        match AssertUnwindSafe(run).catch_unwind().await {
+            Ok(response) => Dispatch {
+                response,
+                route: Some(endpoint.pattern.clone()),
+                panic: None,
+            },
+            Err(payload) => {
+                let panic = HandlerPanic {
+                    method,
+                    uri,
+                    route: endpoint.pattern.clone(),
+                    message: panic_message(payload.as_ref()),
+                };
+                Dispatch {
+                    response: self.panic_response(&panic),
+                    route: Some(endpoint.pattern.clone()),
+                    panic: Some(panic),
                 }
             }
+        }
+    }
+
+    /// Answers a request no route matched: automatic `OPTIONS`, a static
+    /// file for `GET` and `HEAD`, 405 when the path exists under other
+    /// methods, 501 for methods nothing is registered for, and 404 otherwise.
+    fn unmatched(&self, method: &HttpMethod, uri: &str) -> Response {
+        let allowed = self.allowed_methods(uri);
+        if *method == HttpMethod::Options && !allowed.is_empty() {
+            let mut response = Response::new()
+                .status(crate::http::status::HttpStatusCode::NoContent)
+                .header("Allow", allow_header(&allowed))
+                .build();
+            // A 204 must not carry Content-Length (RFC 9110 §8.6).
+            response.headers_mut().remove("Content-Length");
+            return response;
+        }
 
+        if matches!(method, HttpMethod::Get | HttpMethod::Head)
+            && let Some(filename) = self.static_file(uri)
+        {
+            return serve_static(&filename);
+        }
+
+        if !allowed.is_empty() {
+            Response::new()
+                .status(crate::http::status::HttpStatusCode::MethodNotAllowed)
+                .header("Allow", allow_header(&allowed))
+                .build()
+        } else if matches!(method, HttpMethod::Extension(_)) && !self.routes.contains_key(method) {
+            Response::new()
+                .status(crate::http::status::HttpStatusCode::NotImplemented)
+                .build()
+        } else {
             Response::new()
                 .status(crate::http::status::HttpStatusCode::NotFound)
                 .build()
         }
     }
+
+    /// Methods with a route matching `uri`, or every registered method for
+    /// `*`. Static files count as `GET` routes. `HEAD` comes with `GET` and
+    /// `OPTIONS` with anything, since both are answered automatically. Empty
+    /// if nothing matches.
+    fn allowed_methods(&self, uri: &str) -> Vec<HttpMethod> {
+        let mut allowed: Vec<HttpMethod> = self
+            .routes
+            .keys()
+            .filter(|method| uri == "*" || self.find(method, uri).is_some())
+            .cloned()
+            .collect();
+        if !allowed.contains(&HttpMethod::Get) && self.static_file(uri).is_some() {
+            allowed.push(HttpMethod::Get);
+        }
+        if allowed.is_empty() {
+            return allowed;
+        }
+
+        if allowed.contains(&HttpMethod::Get) && !allowed.contains(&HttpMethod::Head) {
+            allowed.push(HttpMethod::Head);
+        }
+        if !allowed.contains(&HttpMethod::Options) {
+            allowed.push(HttpMethod::Options);
+        }
+        allowed.sort_by_key(|method| {
+            let rank = match method {
+                HttpMethod::Get => 0,
+                HttpMethod::Head => 1,
+                HttpMethod::Post => 2,
+                HttpMethod::Put => 3,
+                HttpMethod::Patch => 4,
+                HttpMethod::Delete => 5,
+                HttpMethod::Options => 6,
+                HttpMethod::Trace => 7,
+                HttpMethod::Connect => 8,
+                HttpMethod::Extension(_) => 9,
+            };
+            (rank, method.to_string())
+        });
+        allowed
+    }
+
+    /// The file under a [`serve_dir`](Self::serve_dir) directory that `uri`
+    /// names, if there is one. Files that resolve to somewhere outside the
+    /// directory, through `..` or a symlink, are not served.
+    fn static_file(&self, uri: &str) -> Option<String> {
+        self.static_routes.iter().find_map(|(prefix, dir)| {
+            let rest = uri.strip_prefix(prefix.as_str())?;
+            let root = format!(".{}", dir);
+            let filename = format!("{}/{}", root, rest);
+            let canonical = Path::new(&filename).canonicalize().ok()?;
+            let inside = canonical.starts_with(Path::new(&root).canonicalize().ok()?);
+            (inside && canonical.is_file()).then_some(filename)
+        })
+    }
+
+    fn panic_response(&self, panic: &HandlerPanic) -> Response {
+        let mut response = match &self.panic_handler {
+            Some(PanicHandler(handler)) => handler(panic),
+            None => Response::new()
+                .status(crate::http::status::HttpStatusCode::InternalServerError)
+                .build(),
+        };
+        response
+            .headers_mut()
+            .insert("Connection".into(), "close".into());
+        response
+    }
+}
+
+fn serve_static(filename: &str) -> Response {
+    let Ok(mut file) = File::open(filename) else {
+        return Response::new()
+            .status(crate::http::status::HttpStatusCode::NotFound)
+            .build();
+    };
+    let mut buf = Vec::new();
+    if file.read_to_end(&mut buf).is_err() {
+        return Response::new()
+            .status(crate::http::status::HttpStatusCode::InternalServerError)
+            .build();
+    }
+    let mime_type = mime_guess::from_path(filename)
+        .first_or_text_plain()
+        .to_string();
+    Response::new()
+        .body_raw(buf)
+        .header("Content-Type", mime_type.as_str())
+        .build()
+}
+
+fn allow_header(methods: &[HttpMethod]) -> String {
+    methods
+        .iter()
+        .map(HttpMethod::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, uri: &str) -> Request {
+        format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, uri)
+            .parse()
+            .unwrap()
+    }
+
+    fn body(response: &Response) -> &[u8] {
+        match response.body() {
+            crate::response::Body::Full(body) => body,
+            _ => panic!("streaming body"),
+        }
+    }
+
+    /// A router serving `target/<name>` under `assets`, with `app.js` in it.
+    fn static_router(name: &str) -> Router {
+        let dir = format!("target/{}-{}", name, std::process::id());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(format!("{}/app.js", dir), "let x = 1;").unwrap();
+        Router::new()
+            .get("items", async || Response::new().body("items").build())
+            .post("items", async || Response::new().body("created").build())
+            .serve_dir("assets", &dir)
+    }
+
+    #[tokio::test]
+    async fn answers_405_with_allow() {
+        let router = static_router("router-405");
+        let response = router.handle(request("DELETE", "/items")).await;
+        assert_eq!(response.status().usize(), 405);
+        assert_eq!(
+            response.header("Allow").map(String::as_str),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_options_automatically() {
+        let router = static_router("router-options");
+        let response = router.handle(request("OPTIONS", "/items")).await;
+        assert_eq!(response.status().usize(), 204);
+        assert_eq!(
+            response.header("Allow").map(String::as_str),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+
+        let response = router.handle(request("OPTIONS", "/assets/app.js")).await;
+        assert_eq!(response.status().usize(), 204);
+        assert_eq!(
+            response.header("Allow").map(String::as_str),
+            Some("GET, HEAD, OPTIONS")
+        );
+
+        let response = router.handle(request("OPTIONS", "/missing")).await;
+        assert_eq!(response.status().usize(), 404);
+    }
+
+    #[tokio::test]
+    async fn serves_static_files_to_get_and_head_only() {
+        let router = static_router("router-static");
+        let response = router.handle(request("GET", "/assets/app.js")).await;
+        assert_eq!(response.status().usize(), 200);
+        assert_eq!(body(&response), b"let x = 1;");
+
+        let response = router.handle(request("HEAD", "/assets/app.js")).await;
+        assert_eq!(response.status().usize(), 200);
+        assert!(body(&response).is_empty());
+
+        for method in ["POST", "PUT", "DELETE"] {
+            let response = router.handle(request(method, "/assets/app.js")).await;
+            assert_eq!(response.status().usize(), 405, "{}", method);
+            assert_eq!(
+                response.header("Allow").map(String::as_str),
+                Some("GET, HEAD, OPTIONS")
+            );
+            assert!(body(&response).is_empty());
+        }
+
+        let response = router.handle(request("GET", "/assets/missing.js")).await;
+        assert_eq!(response.status().usize(), 404);
+    }
+
+    #[tokio::test]
+    async fn keeps_static_files_inside_their_directory() {
+        let router = static_router("router-escape");
+        let dir = format!("target/router-escape-{}", std::process::id());
+        let link = format!("{}/escape.toml", dir);
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(std::fs::canonicalize("Cargo.toml").unwrap(), &link).unwrap();
+
+        let response = router.handle(request("GET", "/assets/escape.toml")).await;
+        assert_eq!(response.status().usize(), 404);
+        // The parser resolves `..` before routing; unresolved, it still
+        // can't leave the directory.
+        assert!(router.static_file("/assets/../../Cargo.toml").is_none());
+        assert!(router.static_file("/assets/app.js").is_some());
+    }
 }
@@ -0,0 +1,63 @@
+use std::env;
+use std::io;
+use std::os::fd::RawFd;
+use std::os::unix::process::parent_id;
+use std::sync::Mutex;
+
+use crate::listener::Listener;
+use crate::upgrade;
+
+/// First file descriptor passed by systemd; the rest follow in order.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Descriptors already taken, so none is wrapped (and later closed) twice.
+static TAKEN: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// Listening sockets passed in through systemd socket activation
+/// (`LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`), or by the old process
+/// during an upgrade.
+///
+/// With a `name`, only sockets whose `FileDescriptorName=` matches are
+/// taken. Returns nothing if the variables are missing or meant for another
+/// process; each socket is only ever returned once.
+pub(crate) fn listen_fds(name: Option<&str>) -> io::Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+
+    // An old process handing over its sockets during an upgrade can't know
+    // our pid up front, so it sends its own instead.
+    let from_parent = env::var(upgrade::PARENT_PID)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(parent_id());
+    let for_us = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    if !for_us && !from_parent {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = fds
+        .as_deref()
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS"))?;
+    let names: Vec<&str> = names
+        .as_deref()
+        .map_or_else(Vec::new, |n| n.split(':').collect());
+
+    let mut taken = TAKEN.lock().unwrap_or_else(|e| e.into_inner());
+    let mut listeners = Vec::new();
+    for i in 0..count {
+        let fd = SD_LISTEN_FDS_START + i;
+        // The old process already picked the sockets it passes on.
+        let wanted = match name {
+            Some(name) if !from_parent => names.get(i as usize) == Some(&name),
+            _ => true,
+        };
+        if wanted && !taken.contains(&fd) {
+            // SAFETY: systemd hands these descriptors to this process, and
+            // `taken` makes sure nothing else in it owns them.
+            listeners.push(unsafe { Listener::from_raw_fd(fd) }?);
+            taken.push(fd);
+        }
+    }
+    Ok(listeners)
+}
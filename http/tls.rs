@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, ServerConfig};
+
+/// Certificates for serving an [`App`](crate::App) over HTTPS.
+///
+/// The default certificate is used when the client sends no SNI name or one
+/// that matches none of the names added with [`sni`](Self::sni).
+///
+/// ```no_run
+/// # use server::tls::TlsConfig;
+/// let tls = TlsConfig::new("certs/default.pem", "certs/default.key")
+///     .sni("api.example.com", "certs/api.pem", "certs/api.key");
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    default: (PathBuf, PathBuf),
+    sni: Vec<(String, PathBuf, PathBuf)>,
+}
+
+impl TlsConfig {
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        Self {
+            default: (cert.as_ref().to_path_buf(), key.as_ref().to_path_buf()),
+            sni: Vec::new(),
+        }
+    }
+
+    /// Serves `cert` to clients asking for `hostname` via SNI.
+    pub fn sni(
+        mut self,
+        hostname: impl Into<String>,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Self {
+        self.sni.push((
+            hostname.into(),
+            cert.as_ref().to_path_buf(),
+            key.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
+    /// Loads every certificate and key and builds the rustls server config.
+    pub fn server_config(&self) -> io::Result<ServerConfig> {
+        let default = load_certified_key(&self.default.0, &self.default.1)?;
+        let mut by_name = HashMap::new();
+        for (name, cert, key) in &self.sni {
+            by_name.insert(name.to_ascii_lowercase(), load_certified_key(cert, key)?);
+        }
+
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(invalid_data)?
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(SniResolver { default, by_name }));
+
+        Ok(config)
+    }
+
+    /// Builds an acceptor advertising `alpn`, in order of preference.
+    pub(crate) fn acceptor(&self, alpn: Vec<Vec<u8>>) -> io::Result<TlsAcceptor> {
+        let mut config = self.server_config()?;
+        config.alpn_protocols = alpn;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(key))
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .map_err(|e| pem_error(cert, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| pem_error(cert, e))?;
+    if chain.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", cert.display()),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let signing_key = any_supported_type(&key).map_err(invalid_data)?;
+
+    Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+fn invalid_data(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// What was negotiated on a TLS connection, available to handlers through
+/// [`Request::connection`](crate::request::Request::connection).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// e.g. `TLSv1_3`.
+    pub version: Option<String>,
+    /// e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub cipher_suite: Option<String>,
+    /// The ALPN protocol, e.g. `h2`.
+    pub alpn: Option<String>,
+    /// The name the client asked for via SNI.
+    pub server_name: Option<String>,
+}
+
+impl TlsInfo {
+    pub(crate) fn from_connection(conn: &rustls::ServerConnection) -> Self {
+        Self {
+            version: conn.protocol_version().map(|v| format!("{:?}", v)),
+            cipher_suite: conn
+                .negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite())),
+            alpn: conn
+                .alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+            server_name: conn.server_name().map(str::to_string),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::env;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::os::unix::process::parent_id;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+
+use crate::listener::Listener;
+
+/// Set by the old process to its own pid, so the new one can tell the
+/// inherited descriptors were meant for it and not for a grandchild.
+pub(crate) const PARENT_PID: &str = "UPGRADE_PARENT_PID";
+
+/// Descriptor the new process writes to once it is listening.
+const READY_FD: &str = "UPGRADE_READY_FD";
+
+/// First descriptor handed to the new process, as with systemd.
+const FIRST_FD: RawFd = 3;
+
+/// A new process started by [`spawn`], not yet known to be serving.
+#[derive(Debug)]
+pub(crate) struct Upgrade {
+    child: Child,
+    ready: UnixStream,
+}
+
+impl Upgrade {
+    pub(crate) fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Waits for the new process to report that it is listening. Returns
+    /// `false` if it exited or closed the channel first.
+    pub(crate) async fn ready(&mut self) -> bool {
+        let ready = matches!(self.ready.read_u8().await, Ok(1));
+        if !ready {
+            // Reap it so a failed upgrade doesn't leave a zombie.
+            let _ = self.child.wait().await;
+        }
+        ready
+    }
+}
+
+/// Starts the current executable again, with the same arguments, and hands
+/// it `listeners` the way systemd socket activation does.
+///
+/// The program is `argv[0]` rather than the resolved path of the running
+/// binary, so a binary replaced on disk by a deploy is the one that starts.
+pub(crate) fn spawn(listeners: &[Listener]) -> io::Result<Upgrade> {
+    let mut args = env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => env::current_exe()?.into_os_string(),
+    };
+    let (ours, theirs) = StdUnixStream::pair()?;
+
+    // Descriptors are moved into place after fork, where nothing may
+    // allocate, so everything is prepared here.
+    let mut fds: Vec<RawFd> = listeners.iter().map(Listener::as_raw_fd).collect();
+    fds.push(theirs.as_raw_fd());
+    let mut moved = vec![-1; fds.len()];
+    let count = listeners.len();
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("LISTEN_FDS", count.to_string())
+        .env(PARENT_PID, std::process::id().to_string())
+        .env(READY_FD, (FIRST_FD + count as RawFd).to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDNAMES");
+    // SAFETY: the closure only makes async-signal-safe calls and doesn't
+    // allocate.
+    unsafe {
+        command.pre_exec(move || {
+            // Copy everything above the target range first, so moving one
+            // descriptor into place can't overwrite another still to be moved.
+            let above = FIRST_FD + fds.len() as RawFd;
+            for (fd, copy) in fds.iter().zip(moved.iter_mut()) {
+                *copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above);
+                if *copy < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            // `dup2` leaves close-on-exec cleared on the new descriptor.
+            for (i, copy) in moved.iter().enumerate() {
+                if libc::dup2(*copy, FIRST_FD + i as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    drop(theirs);
+
+    ours.set_nonblocking(true)?;
+    Ok(Upgrade {
+        child,
+        ready: UnixStream::from_std(ours)?,
+    })
+}
+
+/// In a process started by [`spawn`], tells the old one that it is
+/// listening, so it can stop accepting and drain.
+pub(crate) async fn notify_parent() {
+    static NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+    let from_parent = env::var(PARENT_PID)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(parent_id());
+    let Some(fd) = env::var(READY_FD)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    if !from_parent || NOTIFIED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    // SAFETY: the old process passed this descriptor for this purpose only,
+    // and `NOTIFIED` makes sure it is taken once.
+    let ready = unsafe { StdUnixStream::from_raw_fd(fd) };
+    if ready.set_nonblocking(true).is_ok()
+        && let Ok(mut ready) = UnixStream::from_std(ready)
+    {
+        let _ = ready.write_u8(1).await;
+    }
+}
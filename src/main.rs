@@ -24,7 +24,7 @@ async fn main() {
             })
             .serve_dir("/", "/assets"),
     )
-    .listen(true)
+    .listen(server::LogLevel::All)
     .await
     .unwrap();
 }